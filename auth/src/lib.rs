@@ -72,6 +72,8 @@ pub enum Error {
     HttpStatus(#[from] HttpStatusError),
     #[error(transparent)]
     Http(#[from] http::Error),
+    #[error(transparent)]
+    Session(#[from] SessionError),
 }
 
 #[derive(Debug)]
@@ -91,6 +93,33 @@ impl From<StatusCode> for HttpStatusError {
     }
 }
 
+const SESSION_SERVER: &str = "https://sessionserver.mojang.com/session/minecraft";
+
+/// An error sent by the session server, like a `ForbiddenOperationException`
+/// if the access token is invalid.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, thiserror::Error)]
+#[serde(rename_all = "camelCase")]
+#[error("{error}: {error_message}")]
+pub struct SessionError {
+    pub error: String,
+    #[serde(default)]
+    pub error_message: String,
+}
+
+/// Like `error_for_status`, but returns the error sent by the session server if there is one.
+#[allow(clippy::result_large_err)]
+fn session_error_for_status<T: AsRef<[u8]>>(
+    resp: http::Response<T>,
+) -> Result<http::Response<T>, Error> {
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    match serde_json::from_slice::<SessionError>(resp.body().as_ref()) {
+        Ok(e) => Err(e.into()),
+        Err(_) => Err(HttpStatusError::from(resp.status()).into()),
+    }
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Auth {
     pub name: String,
@@ -99,6 +128,79 @@ pub struct Auth {
     pub ms_auth: MsAuth,
 }
 
+impl Auth {
+    /// Tells the session server that this account is joining the server
+    /// with `server_hash`, as required by online mode servers before
+    /// sending the `EncryptionResponse`.
+    pub async fn join(&self, server_hash: &str, client: &impl HttpClient) -> Result<(), Error> {
+        let json = json!({
+            "accessToken": self.token,
+            "selectedProfile": self.uuid,
+            "serverId": server_hash,
+        });
+        let resp = client
+            .execute_request(
+                http::request::Builder::new()
+                    .uri(format!("{SESSION_SERVER}/join"))
+                    .method(http::Method::POST)
+                    .header("content-type", "application/json")
+                    .body(serde_json::to_vec(&json)?)?,
+            )
+            .await?;
+        session_error_for_status(resp)?;
+        Ok(())
+    }
+}
+
+/// The profile of a player, as returned by the session server.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GameProfile {
+    /// The uuid without dashes.
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub properties: Vec<ProfileProperty>,
+}
+
+impl GameProfile {
+    /// The property called `name`, like `textures` holding the skin and cape.
+    pub fn property(&self, name: &str) -> Option<&ProfileProperty> {
+        self.properties.iter().find(|property| property.name == name)
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileProperty {
+    pub name: String,
+    pub value: String,
+    pub signature: Option<String>,
+}
+
+/// Asks the session server whether `username` has joined the server with
+/// `server_hash`, returning the profile of the player if they have.
+pub async fn has_joined(
+    username: &str,
+    server_hash: &str,
+    client: &impl HttpClient,
+) -> Result<Option<GameProfile>, Error> {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("username", username)
+        .append_pair("serverId", server_hash)
+        .finish();
+    let resp = client
+        .execute_request(
+            http::request::Builder::new()
+                .uri(format!("{SESSION_SERVER}/hasJoined?{query}"))
+                .body(Vec::new())?,
+        )
+        .await?;
+    let resp = session_error_for_status(resp)?;
+    if resp.status() == StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_slice(resp.into_body().as_ref())?))
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct McProfile {
     id: String,
@@ -442,3 +544,89 @@ impl DeviceCode {
         Ok(auth)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Answers every request with the same response, remembering the last uri.
+    struct MockSession {
+        status: StatusCode,
+        body: &'static str,
+        uri: Mutex<Option<http::Uri>>,
+    }
+
+    impl MockSession {
+        fn new(status: u16, body: &'static str) -> Self {
+            MockSession {
+                status: StatusCode::from_u16(status).unwrap(),
+                body,
+                uri: Mutex::new(None),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl HttpClient for MockSession {
+        type Body = &'static [u8];
+
+        async fn execute_request(
+            &self,
+            req: http::Request<Vec<u8>>,
+        ) -> anyhow::Result<http::response::Response<Self::Body>> {
+            *self.uri.lock().unwrap() = Some(req.uri().clone());
+            Ok(http::Response::builder()
+                .status(self.status)
+                .body(self.body.as_bytes())?)
+        }
+    }
+
+    #[tokio::test]
+    async fn has_joined_profile() {
+        let session = MockSession::new(
+            200,
+            r#"{"id":"069a79f444e94726a5befca90e38aaf5","name":"steve","properties":[{"name":"textures","value":"e30=","signature":"c2ln"}]}"#,
+        );
+        let profile = has_joined("steve", "-1a&b", &session).await.unwrap().unwrap();
+        assert_eq!(profile.name, "steve");
+        assert_eq!(profile.property("textures").unwrap().value, "e30=");
+        let uri = session.uri.lock().unwrap().clone().unwrap();
+        assert_eq!(uri.query(), Some("username=steve&serverId=-1a%26b"));
+    }
+
+    #[tokio::test]
+    async fn has_joined_no_content() {
+        let session = MockSession::new(204, "");
+        assert_eq!(has_joined("steve", "hash", &session).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn session_errors() {
+        let auth = Auth {
+            token: "expired".into(),
+            ..Default::default()
+        };
+        let session = MockSession::new(
+            403,
+            r#"{"error":"ForbiddenOperationException","errorMessage":"Invalid token."}"#,
+        );
+        match auth.join("hash", &session).await {
+            Err(Error::Session(e)) => assert_eq!(e.error, "ForbiddenOperationException"),
+            res => panic!("expected a session error, got {res:?}"),
+        }
+
+        // errors without a body fall back to the status
+        let session = MockSession::new(503, "");
+        assert!(matches!(
+            auth.join("hash", &session).await,
+            Err(Error::HttpStatus(_))
+        ));
+
+        let session = MockSession::new(200, "{\"id\":");
+        assert!(matches!(
+            has_joined("steve", "hash", &session).await,
+            Err(Error::Serde(_))
+        ));
+    }
+}
@@ -1 +1,122 @@
 #![deny(clippy::undocumented_unsafe_blocks)]
+//! Chat components, the JSON text format used for chat messages,
+//! disconnect reasons and server descriptions.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_derive::{Deserialize, Serialize};
+
+/// A chat component with its children in `extra`.
+///
+/// Deserializes from plain strings and arrays as well, where the first
+/// element of an array is the parent of the following ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(remote = "Self")]
+pub struct Component {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translate: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub with: Vec<Component>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bold: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub italic: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub underlined: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strikethrough: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub obfuscated: Option<bool>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra: Vec<Component>,
+}
+
+impl Component {
+    pub fn text(text: impl Into<String>) -> Self {
+        Component {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The text without formatting, translations are replaced by their key.
+    pub fn to_plain(&self) -> String {
+        let mut plain = String::new();
+        self.push_plain(&mut plain);
+        plain
+    }
+
+    fn push_plain(&self, plain: &mut String) {
+        plain.push_str(&self.text);
+        if let Some(translate) = &self.translate {
+            plain.push_str(translate);
+        }
+        for child in &self.extra {
+            child.push_plain(plain);
+        }
+    }
+}
+
+impl From<&str> for Component {
+    fn from(text: &str) -> Self {
+        Component::text(text)
+    }
+}
+
+impl From<String> for Component {
+    fn from(text: String) -> Self {
+        Component::text(text)
+    }
+}
+
+impl Serialize for Component {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Component::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Component {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Text(String),
+            Array(Vec<Component>),
+            Object(#[serde(deserialize_with = "Component::deserialize")] Component),
+        }
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Text(text) => Component::text(text),
+            Repr::Array(components) => {
+                let mut components = components.into_iter();
+                let mut parent = components.next().unwrap_or_default();
+                parent.extra.extend(components);
+                parent
+            }
+            Repr::Object(component) => component,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deserialize() {
+        let plain: Component = serde_json::from_str(r#""hello""#).unwrap();
+        assert_eq!(plain, Component::text("hello"));
+
+        let nested: Component = serde_json::from_str(
+            r#"{"text":"a","bold":true,"extra":["b",[{"text":"c"},{"translate":"d"}]]}"#,
+        )
+        .unwrap();
+        assert_eq!(nested.bold, Some(true));
+        assert_eq!(nested.to_plain(), "abcd");
+
+        let json = serde_json::to_string(&nested).unwrap();
+        assert_eq!(serde_json::from_str::<Component>(&json).unwrap(), nested);
+    }
+}
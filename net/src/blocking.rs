@@ -0,0 +1,284 @@
+//! A blocking counterpart to `conn`, for use without an async executor.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+use aes::cipher::InvalidLength;
+
+use miners_packet::Direction;
+
+use crate::capture::Capture;
+use crate::codec::{EncryptionError, FrameDecoder, FrameEncoder, ReadError, ReadLimits};
+use crate::encoding::{EncodedData, Encoder};
+use crate::packing::PreparedPacket;
+use crate::stats::TrafficStats;
+
+/// A united blocking connection.
+/// Like `conn::Connection`, but using `std::io::Read` and `std::io::Write`.
+pub struct BlockingConnection<R, W> {
+    pub read_half: BlockingReadHalf<R>,
+    pub write_half: BlockingWriteHalf<W>,
+}
+
+impl<R: Read, W: Write> BlockingConnection<R, W> {
+    pub fn new(reader: R, writer: W) -> BlockingConnection<BufReader<R>, BufWriter<W>> {
+        BlockingConnection {
+            read_half: BlockingReadHalf::new(BufReader::new(reader)),
+            write_half: BlockingWriteHalf::new(BufWriter::new(writer)),
+        }
+    }
+    pub fn unbuffered(reader: R, writer: W) -> Self {
+        BlockingConnection {
+            read_half: BlockingReadHalf::new(reader),
+            write_half: BlockingWriteHalf::new(writer),
+        }
+    }
+    pub fn split(self) -> (BlockingReadHalf<R>, BlockingWriteHalf<W>) {
+        (self.read_half, self.write_half)
+    }
+
+    /// Enables compression on both halves, as requested by `SetCompression27`.
+    ///
+    /// Packets with a length of at least `threshold` will be compressed,
+    /// a negative threshold disables compression.
+    pub fn enable_compression(&mut self, threshold: i32) {
+        self.read_half.enable_compression(threshold);
+        self.write_half.enable_compression(threshold);
+    }
+
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), EncryptionError> {
+        self.read_half.enable_encryption(key)?;
+        Ok(self.write_half.enable_encryption(key)?)
+    }
+
+    /// Records the packets of both halves to `capture`,
+    /// with `incoming` being the direction of packets read.
+    pub fn set_capture(&mut self, capture: Capture, incoming: Direction) {
+        self.read_half.set_capture(capture.clone(), incoming);
+        self.write_half.set_capture(capture, incoming.opposite());
+    }
+}
+
+/// The reading half of a blocking connection.
+/// Returned from `BlockingConnection::split()`
+pub struct BlockingReadHalf<R> {
+    decoder: FrameDecoder,
+    reader: R,
+    capture: Option<(Capture, Direction)>,
+}
+
+impl<R> BlockingReadHalf<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            decoder: FrameDecoder::new(),
+            reader,
+            capture: None,
+        }
+    }
+
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), EncryptionError> {
+        self.decoder.enable_encryption(key)
+    }
+
+    /// Enables decompression of incoming packets.
+    ///
+    /// A negative threshold disables compression, as with `SetCompression27`.
+    pub fn enable_compression(&mut self, threshold: i32) {
+        self.decoder.enable_compression(threshold)
+    }
+
+    pub fn stats(&self) -> &TrafficStats {
+        self.decoder.stats()
+    }
+
+    pub fn stats_mut(&mut self) -> &mut TrafficStats {
+        self.decoder.stats_mut()
+    }
+
+    pub fn limits(&self) -> &ReadLimits {
+        self.decoder.limits()
+    }
+
+    pub fn set_limits(&mut self, limits: ReadLimits) {
+        self.decoder.set_limits(limits)
+    }
+
+    /// Records every packet read to `capture`, which are sent in `direction`.
+    pub fn set_capture(&mut self, capture: Capture, direction: Direction) {
+        self.capture = Some((capture, direction));
+    }
+
+    /// The capture recording the packets read, if any.
+    pub fn capture(&self) -> Option<&Capture> {
+        self.capture.as_ref().map(|(capture, _)| capture)
+    }
+
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        self.decoder.shrink_to(min_capacity)
+    }
+}
+
+impl<R: Read> BlockingReadHalf<R> {
+    pub fn read_encoded(&mut self) -> Result<EncodedData<'_>, ReadError> {
+        while !self.decoder.advance()? {
+            let wanted = self.decoder.wanted();
+            let read = match self.reader.read(self.decoder.read_buf(wanted)) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                res => res?,
+            };
+            if read == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.decoder.commit(read);
+            self.decoder.decrypt_pending()?;
+        }
+        let frame = self.decoder.frame();
+        if let Some((capture, direction)) = &self.capture {
+            capture.record(*direction, &frame);
+        }
+        Ok(frame)
+    }
+}
+
+/// The writing half of a blocking connection.
+/// Returned from `BlockingConnection::split()`
+pub struct BlockingWriteHalf<W> {
+    encoder: FrameEncoder,
+    /// prepared packets are copied here to be encrypted
+    writebuf: Vec<u8>,
+    writer: W,
+    capture: Option<(Capture, Direction)>,
+}
+
+impl<W> BlockingWriteHalf<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            encoder: FrameEncoder::new(),
+            writebuf: Vec::new(),
+            writer: inner,
+            capture: None,
+        }
+    }
+
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), InvalidLength> {
+        self.encoder.enable_encryption(key)
+    }
+
+    /// Enables compression for packets with a length of at least `threshold`.
+    ///
+    /// A negative threshold disables compression, as with `SetCompression27`.
+    pub fn enable_compression(&mut self, threshold: i32) {
+        self.encoder.enable_compression(threshold)
+    }
+
+    /// Records every packet written to `capture`, which are sent in `direction`.
+    pub fn set_capture(&mut self, capture: Capture, direction: Direction) {
+        self.capture = Some((capture, direction));
+    }
+
+    /// The capture recording the packets written, if any.
+    pub fn capture(&self) -> Option<&Capture> {
+        self.capture.as_ref().map(|(capture, _)| capture)
+    }
+
+    pub fn stats(&self) -> &TrafficStats {
+        self.encoder.stats()
+    }
+
+    pub fn stats_mut(&mut self) -> &mut TrafficStats {
+        self.encoder.stats_mut()
+    }
+}
+
+impl<W: Write> BlockingWriteHalf<W> {
+    pub fn write(&mut self, encoded: EncodedData) -> io::Result<()> {
+        if let Some((capture, direction)) = &self.capture {
+            capture.record(*direction, &encoded);
+        }
+        let (buf, start) = self.encoder.frame(encoded)?;
+        self.writer.write_all(&buf[start..])
+    }
+
+    /// Writes a prepared packet, which only has to be encrypted for this connection.
+    pub fn write_prepared(&mut self, packet: &PreparedPacket) -> io::Result<()> {
+        self.encoder.start_prepared(packet)?;
+        if let Some((capture, direction)) = &self.capture {
+            if let Ok(mut buf) = crate::codec::unpack_prepared(packet) {
+                capture.record(*direction, &EncodedData::new(&mut buf));
+            }
+        }
+        if !self.encoder.is_encrypted() {
+            return self.writer.write_all(packet.frame());
+        }
+        self.writebuf.clear();
+        self.writebuf.extend_from_slice(packet.frame());
+        self.encoder.encrypt(&mut self.writebuf)?;
+        self.writer.write_all(&self.writebuf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn write_packet<P>(
+        &mut self,
+        version: miners_version::ProtocolVersion,
+        packet: P,
+        encoder: &mut Encoder,
+    ) -> miners_encoding::encode::Result<()>
+    where
+        P: miners_packet::Packet,
+    {
+        if let Some(res) = encoder.encode_packet(version, packet) {
+            match res {
+                Ok(encoded) => Ok(self.write(encoded)?),
+                Err(e) => Err(e),
+            }
+        } else {
+            #[cfg(debug_assertions)]
+            eprintln!(
+                "tried to write packet of type {0} in mismatching protocol version {version}",
+                std::any::type_name::<P>(),
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miners_encoding::attrs::Rest;
+
+    struct CountingReader<'a>(&'a [u8], usize);
+
+    impl Read for CountingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.1 += 1;
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn multiple_frames_per_read() {
+        let mut write_half = BlockingWriteHalf::new(vec![]);
+        write_half.enable_encryption(&[5; 16]).unwrap();
+        let mut encoder = Encoder::new();
+        for id in 0..10 {
+            let encoded = encoder
+                .encode(id, Rest::from(&[id as u8; 100][..]))
+                .unwrap();
+            write_half.write(encoded).unwrap();
+        }
+
+        let written = write_half.writer;
+        let mut read_half = BlockingReadHalf::new(CountingReader(&written, 0));
+        read_half.enable_encryption(&[5; 16]).unwrap();
+        for id in 0..10 {
+            let packet = read_half.read_encoded().unwrap().into_packet().unwrap();
+            assert_eq!(packet.id, id);
+            assert_eq!(packet.data, &[id as u8; 100][..]);
+        }
+        assert_eq!(read_half.reader.1, 1);
+        assert!(read_half.read_encoded().is_err());
+    }
+}
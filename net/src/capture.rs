@@ -0,0 +1,387 @@
+//! Recording and replaying the packets of connections.
+//!
+//! A `Capture` is attached to the halves of a connection using `set_capture`
+//! and records every packet read or written to a capture file. The file can
+//! later be read with a `CaptureReader`, passing the records to the parser
+//! matching their state and direction, like `miners_protocol::netty::parse`.
+//!
+//! # Format
+//!
+//! All integers are big endian. A capture file starts with a header
+//!
+//! | magic `MNRSCAP\0` | format version: u16 |
+//!
+//! which is followed by any amount of records
+//!
+//! | timestamp: u64 | direction: u8 | state: u8 | protocol version: i32 | id: i32 | length: u32 | data |
+//!
+//! where the timestamp is in microseconds since the unix epoch, direction
+//! is `0` for clientbound and `1` for serverbound, state is `0` for
+//! handshaking, `1` for status, `2` for login and `3` for play, and data
+//! is the decrypted and decompressed packet data of `length` bytes.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use miners_packet::{Direction, RawPacket, State};
+use miners_version::ProtocolVersion;
+
+use crate::encoding::EncodedData;
+
+const MAGIC: &[u8; 8] = b"MNRSCAP\0";
+const FORMAT_VERSION: u16 = 1;
+
+/// The maximum length of the data of a record, the maximum packet length
+const MAX_DATA_LENGTH: u32 = 1024 * 1024 * 8;
+
+/// A recorded packet.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub timestamp: SystemTime,
+    pub direction: Direction,
+    pub state: State,
+    pub version: ProtocolVersion,
+    pub id: i32,
+    pub data: Vec<u8>,
+}
+
+impl Record {
+    pub fn packet(&self) -> RawPacket<'_> {
+        RawPacket::new(self.id, &self.data)
+    }
+}
+
+/// Writes records to a capture file.
+pub struct CaptureWriter<W> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Writes the header to `writer`.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_be_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn write(
+        &mut self,
+        timestamp: SystemTime,
+        direction: Direction,
+        state: State,
+        version: ProtocolVersion,
+        packet: &RawPacket,
+    ) -> io::Result<()> {
+        let micros = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut header = [0u8; 22];
+        header[..8].copy_from_slice(&micros.to_be_bytes());
+        header[8] = match direction {
+            Direction::Clientbound => 0,
+            Direction::Serverbound => 1,
+        };
+        header[9] = match state {
+            State::Handshaking => 0,
+            State::Status => 1,
+            State::Login => 2,
+            State::Play => 3,
+        };
+        header[10..14].copy_from_slice(&version.to_be_bytes());
+        header[14..18].copy_from_slice(&packet.id.to_be_bytes());
+        header[18..].copy_from_slice(&(packet.data.len() as u32).to_be_bytes());
+        self.writer.write_all(&header)?;
+        self.writer.write_all(packet.data)
+    }
+
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.write(
+            record.timestamp,
+            record.direction,
+            record.state,
+            record.version,
+            &record.packet(),
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads the records of a capture file.
+pub struct CaptureReader<R> {
+    reader: R,
+}
+
+fn invalid(msg: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Reads and validates the header from `reader`.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 10];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not a capture file"));
+        }
+        let version = u16::from_be_bytes([header[8], header[9]]);
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!("unsupported capture format {version}")));
+        }
+        Ok(Self { reader })
+    }
+
+    /// Reads the next record, `None` if the capture has ended.
+    pub fn read_record(&mut self) -> io::Result<Option<Record>> {
+        let mut header = [0u8; 22];
+        // a capture may only end in between records
+        match self.reader.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.reader.read_exact(&mut header[1..])?,
+        }
+        let micros = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let direction = match header[8] {
+            0 => Direction::Clientbound,
+            1 => Direction::Serverbound,
+            other => return Err(invalid(format!("invalid direction {other}"))),
+        };
+        let state = match header[9] {
+            0 => State::Handshaking,
+            1 => State::Status,
+            2 => State::Login,
+            3 => State::Play,
+            other => return Err(invalid(format!("invalid state {other}"))),
+        };
+        let version = i32::from_be_bytes(header[10..14].try_into().unwrap());
+        let version = ProtocolVersion::new(version).map_err(invalid)?;
+        let id = i32::from_be_bytes(header[14..18].try_into().unwrap());
+        let len = u32::from_be_bytes(header[18..].try_into().unwrap());
+        if len > MAX_DATA_LENGTH {
+            return Err(invalid(format!("record of {len} bytes is too large")));
+        }
+        let mut data = vec![0; len as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(Some(Record {
+            timestamp: UNIX_EPOCH + Duration::from_micros(micros),
+            direction,
+            state,
+            version,
+            id,
+            data,
+        }))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// A handle to a capture file shared by the halves of a connection,
+/// cloning it is cheap.
+///
+/// The capture doesn't know about the packets it records, the current state
+/// and protocol version are kept up to date by the connections of the crate
+/// on each transition, or by hand using `set_state` and `set_version`.
+/// If writing fails, the capture stops recording instead of failing the
+/// connection, and the error is returned by the next `flush`.
+#[derive(Clone)]
+pub struct Capture(Arc<Mutex<Tap>>);
+
+struct Tap {
+    writer: Option<CaptureWriter<Box<dyn Write + Send>>>,
+    /// Why the capture stopped recording, until it is flushed.
+    error: Option<io::Error>,
+    state: State,
+    version: ProtocolVersion,
+}
+
+impl Capture {
+    /// Starts a capture file in `writer`, which should be buffered.
+    pub fn new(
+        writer: impl Write + Send + 'static,
+        state: State,
+        version: ProtocolVersion,
+    ) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        Ok(Capture(Arc::new(Mutex::new(Tap {
+            writer: Some(CaptureWriter::new(writer)?),
+            error: None,
+            state,
+            version,
+        }))))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tap> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn set_state(&self, state: State) {
+        self.lock().state = state;
+    }
+
+    pub fn set_version(&self, version: ProtocolVersion) {
+        self.lock().version = version;
+    }
+
+    /// Whether the capture is still recording.
+    pub fn is_recording(&self) -> bool {
+        self.lock().writer.is_some()
+    }
+
+    /// Flushes the capture file, or returns the error which stopped the
+    /// recording if it hasn't been returned yet.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut tap = self.lock();
+        if let Some(e) = tap.error.take() {
+            return Err(e);
+        }
+        match &mut tap.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn record(&self, direction: Direction, encoded: &EncodedData) {
+        let mut tap = self.lock();
+        let Tap {
+            writer,
+            error,
+            state,
+            version,
+        } = &mut *tap;
+        let Some(capture) = writer else {
+            return;
+        };
+        let res = match encoded.to_packet() {
+            Ok(packet) => capture.write(SystemTime::now(), direction, *state, *version, &packet),
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+        if let Err(e) = res {
+            *writer = None;
+            *error = Some(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking::{BlockingReadHalf, BlockingWriteHalf};
+    use crate::encoding::Encoder;
+    use crate::memory::SharedBuffer;
+    use crate::packing::PreparedPacket;
+    use miners_encoding::attrs::Rest;
+    use miners_protocol::netty::{
+        login::{clientbound::Disconnect0, CbLogin},
+        parse, AnyPacket,
+    };
+
+    #[test]
+    fn record_and_replay() {
+        let version = ProtocolVersion::new(47).unwrap();
+        let file = SharedBuffer::default();
+        let capture = Capture::new(file.clone(), State::Login, version).unwrap();
+
+        let mut written = Vec::new();
+        let mut write_half = BlockingWriteHalf::new(&mut written);
+        write_half.set_capture(capture.clone(), Direction::Clientbound);
+        let mut encoder = Encoder::new();
+        let disconnect = CbLogin::Disconnect0(Disconnect0 {
+            reason: r#"{"text":"bye"}"#.into(),
+        });
+        write_half
+            .write_packet(version, disconnect, &mut encoder)
+            .unwrap();
+
+        let mut read_half = BlockingReadHalf::new(&written[..]);
+        read_half.set_capture(capture.clone(), Direction::Clientbound);
+        read_half.read_encoded().unwrap();
+        capture.flush().unwrap();
+
+        let file = file.contents();
+        let records = CaptureReader::new(&file[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 2);
+        for record in &records {
+            assert_eq!(record.state, State::Login);
+            assert_eq!(*record.version, 47);
+            let packet = parse(
+                record.state,
+                record.direction,
+                record.packet(),
+                record.version,
+            );
+            match packet.unwrap() {
+                AnyPacket::CbLogin(CbLogin::Disconnect0(disconnect)) => {
+                    assert_eq!(disconnect.reason, r#"{"text":"bye"}"#)
+                }
+                _ => panic!("parsed the wrong packet"),
+            }
+        }
+
+        assert!(CaptureReader::new(&file[..9]).is_err());
+        let mut truncated = CaptureReader::new(&file[..file.len() - 1]).unwrap();
+        assert!(truncated.next().unwrap().is_ok());
+        assert!(truncated.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn stop_on_error() {
+        let version = ProtocolVersion::new(47).unwrap();
+        // room for the header of the file but not for a record
+        let file = io::Cursor::new(vec![0; MAGIC.len() + 2].into_boxed_slice());
+        let capture = Capture::new(file, State::Login, version).unwrap();
+
+        let mut write_half = BlockingWriteHalf::new(io::sink());
+        write_half.set_capture(capture.clone(), Direction::Clientbound);
+        let disconnect = CbLogin::Disconnect0(Disconnect0 {
+            reason: r#"{"text":"bye"}"#.into(),
+        });
+        write_half
+            .write_packet(version, disconnect, &mut Encoder::new())
+            .unwrap();
+
+        assert!(!capture.is_recording());
+        assert!(capture.flush().is_err());
+        // the error is only returned once
+        assert!(capture.flush().is_ok());
+    }
+
+    #[test]
+    fn rejected_prepared_packet_isnt_captured() {
+        let version = ProtocolVersion::new(47).unwrap();
+        let file = SharedBuffer::default();
+        let capture = Capture::new(file.clone(), State::Play, version).unwrap();
+        let mut write_half = BlockingWriteHalf::new(vec![]);
+        write_half.set_capture(capture.clone(), Direction::Clientbound);
+        write_half.enable_compression(64);
+
+        let mut encoder = Encoder::new();
+        let encoded = encoder.encode(3, Rest::from(&[9; 100][..])).unwrap();
+        let packet = PreparedPacket::new(encoded, 256).unwrap();
+        assert!(write_half.write_prepared(&packet).is_err());
+
+        capture.flush().unwrap();
+        let file = file.contents();
+        let mut records = CaptureReader::new(&file[..]).unwrap();
+        assert!(records.next().is_none());
+    }
+}
@@ -0,0 +1,119 @@
+//! A high-throughput CFB8 decryptor.
+//!
+//! Decrypting a byte in CFB8 only depends on the 16 ciphertext bytes preceding
+//! it, which are all known upfront. Unlike encryption, the keystream for many
+//! bytes can therefore be computed at once, letting AES process wide batches
+//! of independent blocks instead of one block after another.
+
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, InvalidLength, KeyInit};
+use aes::{Aes128, Block};
+
+/// The amount of bytes whose keystream is computed in one batch
+const BATCH: usize = 128;
+
+#[derive(Clone)]
+pub(crate) struct Cfb8Decryptor {
+    cipher: Aes128,
+    iv: [u8; 16],
+}
+
+impl Cfb8Decryptor {
+    pub(crate) fn new_from_slices(key: &[u8], iv: &[u8]) -> Result<Self, InvalidLength> {
+        Ok(Self {
+            cipher: Aes128::new_from_slice(key)?,
+            iv: iv.try_into().map_err(|_| InvalidLength)?,
+        })
+    }
+
+    pub(crate) fn decrypt(&mut self, data: &mut [u8]) {
+        // | iv or previous ciphertext | ciphertext of this batch |
+        let mut window = [0u8; 16 + BATCH];
+        let mut blocks = [Block::default(); BATCH];
+        for chunk in data.chunks_mut(BATCH) {
+            let len = chunk.len();
+            window[..16].copy_from_slice(&self.iv);
+            window[16..16 + len].copy_from_slice(chunk);
+
+            for (i, block) in blocks[..len].iter_mut().enumerate() {
+                *block = GenericArray::clone_from_slice(&window[i..i + 16]);
+            }
+            self.cipher.encrypt_blocks(&mut blocks[..len]);
+
+            for (byte, block) in chunk.iter_mut().zip(&blocks) {
+                *byte ^= block[0];
+            }
+            self.iv.copy_from_slice(&window[len..len + 16]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::{BlockDecryptMut, KeyIvInit};
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+
+    fn ciphertext(len: usize) -> Vec<u8> {
+        (0..len as u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 11) as u8)
+            .collect()
+    }
+
+    /// The `cfb8::Decryptor` used before, which decrypts byte by byte
+    fn reference(data: &mut [u8], decryptor: &mut cfb8::Decryptor<Aes128>) {
+        let (chunks, _) = aes::cipher::inout::InOutBuf::from(data).into_chunks();
+        decryptor.decrypt_blocks_inout_mut(chunks);
+    }
+
+    #[test]
+    fn matches_reference() {
+        let data = ciphertext(70000);
+        let mut expected = data.clone();
+        reference(
+            &mut expected,
+            &mut cfb8::Decryptor::new_from_slices(&KEY, &KEY).unwrap(),
+        );
+
+        // decrypt in uneven pieces so batches and calls don't line up
+        let mut actual = data;
+        let mut decryptor = Cfb8Decryptor::new_from_slices(&KEY, &KEY).unwrap();
+        let mut rest = &mut actual[..];
+        let mut size = 0;
+        while !rest.is_empty() {
+            size = (size * 7 + 3) % 1000;
+            let (piece, next) = rest.split_at_mut(size.min(rest.len()));
+            decryptor.decrypt(piece);
+            rest = next;
+        }
+        assert!(expected == actual);
+    }
+
+    /// Run with `cargo test --release -- --ignored --nocapture` to compare throughput.
+    #[test]
+    #[ignore]
+    fn throughput() {
+        const LEN: usize = 32 * 1024 * 1024;
+        let data = ciphertext(LEN);
+
+        let mut expected = data.clone();
+        let mut decryptor = cfb8::Decryptor::new_from_slices(&KEY, &KEY).unwrap();
+        let start = std::time::Instant::now();
+        reference(&mut expected, &mut decryptor);
+        let serial = start.elapsed();
+
+        let mut actual = data;
+        let mut decryptor = Cfb8Decryptor::new_from_slices(&KEY, &KEY).unwrap();
+        let start = std::time::Instant::now();
+        decryptor.decrypt(&mut actual);
+        let batched = start.elapsed();
+
+        assert!(expected == actual);
+        let mib = (LEN / 1024 / 1024) as f64;
+        println!(
+            "serial: {:.1} MiB/s, batched: {:.1} MiB/s",
+            mib / serial.as_secs_f64(),
+            mib / batched.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,1064 @@
+//! Sans-IO framing of the minecraft protocol.
+//!
+//! `FrameDecoder` and `FrameEncoder` own the cipher and zlib state of one
+//! direction of a connection, but never touch any IO themselves. This allows
+//! decoding captured streams or driving the protocol from any kind of
+//! transport, `ReadHalf` and `WriteHalf` are just thin async adapters over them.
+
+use std::io;
+#[cfg(feature = "workpool")]
+use std::time::Duration;
+use std::time::Instant;
+
+use aes::cipher::{InvalidLength, KeyIvInit};
+
+use crate::encoding::{EncodedData, HEADER_SPACE};
+use crate::helpers::{decrypt, encrypt, AsyncCancelled};
+use crate::legacy::{LegacyError, LegacyPing, LegacyStatus};
+use crate::packing::{Compressor, PackedData, PreparedPacket};
+use crate::proxy_protocol::{ProxyHeader, ProxyProtocolError};
+use crate::stats::TrafficStats;
+
+pub(crate) type Encryptor = cfb8::Encryptor<aes::Aes128>;
+pub(crate) type Decryptor = crate::cipher::Cfb8Decryptor;
+
+const INITIAL_BUF_SIZE: usize = 1024;
+
+/// The minimum amount of space offered to a single read
+const MIN_READ_SIZE: usize = 8192;
+
+/// The maximum packet length, 8 MiB
+const MAX_PACKET_LENGTH: u32 = 1024 * 1024 * 8;
+
+/// The maximum frame length, the largest number a 3 byte varint can hold
+const MAX_FRAME_LENGTH: u32 = (1 << 21) - 1;
+
+/// The best compression ratio deflate can achieve, anything claiming
+/// more than that has to be lying about its uncompressed length
+const MAX_DEFLATE_RATIO: u32 = 1032;
+
+/// Limits enforced on incoming packets.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadLimits {
+    /// The maximum length of a frame as sent over the wire.
+    pub max_frame_len: u32,
+    /// The maximum length a compressed packet may declare and inflate to.
+    pub max_uncompressed_len: u32,
+    /// The maximum ratio between the uncompressed and the compressed length.
+    pub max_compression_ratio: u32,
+    /// Rejects uncompressed packets whose length is at least the compression
+    /// threshold, like vanilla servers do.
+    pub validate_uncompressed: bool,
+}
+
+impl Default for ReadLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_len: MAX_FRAME_LENGTH,
+            max_uncompressed_len: MAX_PACKET_LENGTH,
+            max_compression_ratio: MAX_DEFLATE_RATIO,
+            validate_uncompressed: true,
+        }
+    }
+}
+
+/// An error which occurred while reading or decoding a packet.
+#[derive(Debug, thiserror::Error)]
+pub enum ReadError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("varint is longer than 5 bytes")]
+    VarIntTooLong,
+    #[error("the frame length exceeds the maximum frame length! {len} > {max}")]
+    FrameTooLarge { len: u32, max: u32 },
+    #[error("the data length exceeds the maximum packet length! {len} > {max}")]
+    PacketTooLarge { len: u32, max: u32 },
+    #[error("badly compressed packet - size of {len} is below the threshold of {threshold}")]
+    BelowThreshold { len: u32, threshold: u32 },
+    #[error("uncompressed packet - size of {len} is not below the threshold of {threshold}")]
+    AboveThreshold { len: u32, threshold: u32 },
+    #[error("compression ratio exceeded, {compressed} bytes claim to inflate to {uncompressed}")]
+    RatioExceeded { compressed: u32, uncompressed: u32 },
+    #[error("zlib decompression failed: {0}")]
+    Zlib(#[from] flate2::DecompressError),
+    #[error("decompressed length {actual} does not match the declared length {declared}")]
+    LengthMismatch { declared: u32, actual: u64 },
+    #[error(transparent)]
+    Legacy(#[from] LegacyError),
+    #[error(transparent)]
+    ProxyProtocol(#[from] ProxyProtocolError),
+}
+
+impl From<ReadError> for io::Error {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+/// An error which occurred while enabling encryption.
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptionError {
+    #[error("the key is not 16 bytes long")]
+    InvalidLength,
+    #[error("a read was cancelled while decrypting, the connection is unusable")]
+    Cancelled,
+}
+
+impl From<InvalidLength> for EncryptionError {
+    fn from(_: InvalidLength) -> Self {
+        EncryptionError::InvalidLength
+    }
+}
+
+impl From<AsyncCancelled> for EncryptionError {
+    fn from(_: AsyncCancelled) -> Self {
+        EncryptionError::Cancelled
+    }
+}
+
+impl From<AsyncCancelled> for ReadError {
+    fn from(e: AsyncCancelled) -> Self {
+        ReadError::Io(e.into())
+    }
+}
+
+/// Splits a stream of bytes into frames.
+///
+/// Received bytes are passed to `feed`, complete frames are then
+/// returned by `decode`, one at a time.
+pub struct FrameDecoder {
+    decryptor: Option<Option<Box<Decryptor>>>,
+    compression: Option<u32>,
+    limits: ReadLimits,
+    zlib: flate2::Decompress,
+    /// | consumed | decrypted | encrypted | initialized |
+    ///            ^ pos       ^ decrypted ^ filled      ^ buf.len()
+    buf: Vec<u8>,
+    pos: usize,
+    decrypted: usize,
+    filled: usize,
+    frame: Vec<u8>,
+    stats: TrafficStats,
+    /// set while the buffers are lent out to be processed elsewhere
+    #[cfg(feature = "workpool")]
+    busy: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self {
+            decryptor: None,
+            compression: None,
+            limits: ReadLimits::default(),
+            zlib: flate2::Decompress::new(true),
+            buf: Vec::new(),
+            pos: 0,
+            decrypted: 0,
+            filled: 0,
+            frame: Vec::with_capacity(INITIAL_BUF_SIZE),
+            stats: TrafficStats::default(),
+            #[cfg(feature = "workpool")]
+            busy: false,
+        }
+    }
+
+    /// Enables decryption of all bytes following the last decoded frame.
+    ///
+    /// Fails if a read decrypting elsewhere has been cancelled, which leaves
+    /// the decoder without its buffer.
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), EncryptionError> {
+        self.check_busy()?;
+        self.decryptor = Some(Some(Decryptor::new_from_slices(key, key)?.into()));
+        // bytes which have not been decoded yet were already encrypted
+        self.decrypted = self.pos;
+        self.decrypt_pending()?;
+        Ok(())
+    }
+
+    /// Enables decompression of incoming packets.
+    ///
+    /// A negative threshold disables compression, as with `SetCompression27`.
+    pub fn enable_compression(&mut self, threshold: i32) {
+        self.compression = u32::try_from(threshold).ok();
+    }
+
+    pub fn limits(&self) -> &ReadLimits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: ReadLimits) {
+        self.limits = limits;
+    }
+
+    pub fn stats(&self) -> &TrafficStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut TrafficStats {
+        &mut self.stats
+    }
+
+    /// The amount of bytes which have been fed but not yet decoded.
+    pub fn buffered(&self) -> usize {
+        self.filled - self.pos
+    }
+
+    /// Appends received bytes, decrypting them if encryption is enabled.
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), ReadError> {
+        self.check_busy()?;
+        self.read_buf(data.len())[..data.len()].copy_from_slice(data);
+        self.commit(data.len());
+        self.decrypt_pending()?;
+        Ok(())
+    }
+
+    /// Decodes the next frame, returning `None` if more data has to be fed first.
+    pub fn decode(&mut self) -> Result<Option<EncodedData<'_>>, ReadError> {
+        Ok(if self.advance()? {
+            Some(self.frame())
+        } else {
+            None
+        })
+    }
+
+    /// Whether the fed data starts with a legacy ping instead of a frame,
+    /// `None` if more data is needed to tell. Only meaningful before the first frame.
+    pub fn is_legacy_ping(&self) -> Result<Option<bool>, ReadError> {
+        self.check_busy()?;
+        Ok(LegacyPing::detect(&self.buf[self.pos..self.decrypted]))
+    }
+
+    /// Decodes a legacy ping, returning `None` if more data has to be fed first.
+    pub fn decode_legacy_ping(&mut self) -> Result<Option<LegacyPing>, ReadError> {
+        self.check_busy()?;
+        let decoded = LegacyPing::decode(&self.buf[self.pos..self.decrypted])?;
+        Ok(decoded.map(|(ping, len)| {
+            self.pos += len;
+            ping
+        }))
+    }
+
+    /// Decodes the answer to a legacy ping, returning `None` if more data has to be fed first.
+    pub fn decode_legacy_status(&mut self) -> Result<Option<LegacyStatus>, ReadError> {
+        self.check_busy()?;
+        let decoded = LegacyStatus::decode(&self.buf[self.pos..self.decrypted])?;
+        Ok(decoded.map(|(status, len)| {
+            self.pos += len;
+            status
+        }))
+    }
+
+    /// Decodes a PROXY protocol header, returning `None` if more data has to be fed first.
+    pub fn decode_proxy_header(&mut self) -> Result<Option<ProxyHeader>, ReadError> {
+        self.check_busy()?;
+        let decoded = ProxyHeader::decode(&self.buf[self.pos..self.decrypted])?;
+        Ok(decoded.map(|(header, len)| {
+            self.pos += len;
+            header
+        }))
+    }
+
+    /// The amount of bytes needed to make progress on the current frame,
+    /// `0` if a complete frame is buffered.
+    pub(crate) fn wanted(&self) -> usize {
+        let avail = &self.buf[self.pos..self.filled];
+        match parse_varint(avail) {
+            Ok(Some((len, header_len))) => (header_len + len as usize).saturating_sub(avail.len()),
+            _ => 1,
+        }
+    }
+
+    /// Returns the writable space directly behind the fed data,
+    /// at least `min` bytes but usually more, so a single read
+    /// can pull in as many frames as are available.
+    ///
+    /// Unconsumed bytes are only moved to the front once the space behind
+    /// them runs out, and memory is only initialized when the buffer grows.
+    pub(crate) fn read_buf(&mut self, min: usize) -> &mut [u8] {
+        let min = min.max(MIN_READ_SIZE);
+        if self.pos == self.filled {
+            self.pos = 0;
+            self.decrypted = 0;
+            self.filled = 0;
+        } else if self.buf.len() - self.filled < min && self.pos > 0 {
+            self.buf.copy_within(self.pos..self.filled, 0);
+            self.decrypted -= self.pos;
+            self.filled -= self.pos;
+            self.pos = 0;
+        }
+        if self.buf.len() < self.filled + min {
+            self.buf.resize(self.filled + min, 0);
+        }
+        &mut self.buf[self.filled..]
+    }
+
+    /// Marks the first `len` bytes written to `read_buf` as fed, without decrypting them.
+    pub(crate) fn commit(&mut self, len: usize) {
+        self.filled += len;
+        self.stats.wire_bytes += len as u64;
+    }
+
+    #[cfg(feature = "workpool")]
+    pub(crate) fn pending_decryption(&self) -> usize {
+        match self.decryptor {
+            Some(_) => self.filled - self.decrypted,
+            None => 0,
+        }
+    }
+
+    pub(crate) fn decrypt_pending(&mut self) -> Result<(), AsyncCancelled> {
+        self.check_busy()?;
+        if let Some(decryptor) = &mut self.decryptor {
+            let decryptor = decryptor.as_mut().ok_or(AsyncCancelled)?;
+            let start = Instant::now();
+            decrypt(&mut self.buf[self.decrypted..self.filled], decryptor);
+            self.stats.encryption_time += start.elapsed();
+        }
+        self.decrypted = self.filled;
+        Ok(())
+    }
+
+    /// Takes the buffer and the decryptor out of the decoder so the pending
+    /// bytes can be decrypted elsewhere, the range to decrypt is returned as well.
+    ///
+    /// Until `finish_decryption` is called, the decoder is unusable.
+    #[cfg(feature = "workpool")]
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn take_decryption(
+        &mut self,
+    ) -> Result<(Vec<u8>, std::ops::Range<usize>, Box<Decryptor>), AsyncCancelled> {
+        self.check_busy()?;
+        let decryptor = self
+            .decryptor
+            .as_mut()
+            .and_then(Option::take)
+            .ok_or(AsyncCancelled)?;
+        self.busy = true;
+        Ok((
+            std::mem::take(&mut self.buf),
+            self.decrypted..self.filled,
+            decryptor,
+        ))
+    }
+
+    /// Restores the buffer and the decryptor, which took `elapsed` to decrypt.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn finish_decryption(
+        &mut self,
+        buf: Vec<u8>,
+        decryptor: Box<Decryptor>,
+        elapsed: Duration,
+    ) {
+        self.stats.encryption_time += elapsed;
+        self.buf = buf;
+        self.decryptor = Some(Some(decryptor));
+        self.decrypted = self.filled;
+        self.busy = false;
+    }
+
+    /// Takes the buffers out of the decoder so the frame at `range`,
+    /// as returned by `next_frame`, can be unpacked elsewhere.
+    ///
+    /// Until `finish_unpack` is called, the decoder is unusable.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn take_unpack(&mut self, range: std::ops::Range<usize>) -> Unpack {
+        self.busy = true;
+        Unpack {
+            buf: std::mem::take(&mut self.buf),
+            range,
+            frame: std::mem::take(&mut self.frame),
+            compression: self.compression,
+            limits: self.limits,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Restores the buffers, passing through the result of unpacking.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn finish_unpack(
+        &mut self,
+        unpack: Unpack,
+        res: Result<(), ReadError>,
+    ) -> Result<(), ReadError> {
+        self.buf = unpack.buf;
+        self.frame = unpack.frame;
+        self.busy = false;
+        self.stats.compression_time += unpack.elapsed;
+        res?;
+        self.record_frame();
+        Ok(())
+    }
+
+    #[inline]
+    fn check_busy(&self) -> Result<(), AsyncCancelled> {
+        #[cfg(feature = "workpool")]
+        if self.busy {
+            return Err(AsyncCancelled);
+        }
+        Ok(())
+    }
+
+    /// Decodes the next frame into the frame buffer, returning whether there was one.
+    pub(crate) fn advance(&mut self) -> Result<bool, ReadError> {
+        match self.next_frame()? {
+            Some(range) => {
+                self.unpack(range)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Locates the next non-empty frame and marks it as consumed,
+    /// returning the range of its data in the buffer.
+    pub(crate) fn next_frame(&mut self) -> Result<Option<std::ops::Range<usize>>, ReadError> {
+        self.check_busy()?;
+        loop {
+            let avail = &self.buf[self.pos..self.decrypted];
+            let (len, header_len) = match parse_varint(avail)? {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            if len > self.limits.max_frame_len {
+                return Err(ReadError::FrameTooLarge {
+                    len,
+                    max: self.limits.max_frame_len,
+                });
+            }
+            if avail.len() < header_len + len as usize {
+                return Ok(None);
+            }
+            let start = self.pos + header_len;
+            self.pos = start + len as usize;
+            // vanilla skips empty frames
+            if len != 0 {
+                return Ok(Some(start..self.pos));
+            }
+        }
+    }
+
+    /// The uncompressed length declared by the frame at `range`,
+    /// `0` if it isn't compressed.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn declared_len(&self, range: std::ops::Range<usize>) -> u32 {
+        match self.compression {
+            Some(_) => match parse_varint(&self.buf[range]) {
+                Ok(Some((len, _))) => len,
+                _ => 0,
+            },
+            None => 0,
+        }
+    }
+
+    /// Unpacks the frame at `range` into the frame buffer.
+    pub(crate) fn unpack(&mut self, range: std::ops::Range<usize>) -> Result<(), ReadError> {
+        let start = Instant::now();
+        let res = unpack(
+            &self.buf[range],
+            &mut self.frame,
+            self.compression,
+            &self.limits,
+            &mut self.zlib,
+        );
+        if self.compression.is_some() {
+            self.stats.compression_time += start.elapsed();
+        }
+        res?;
+        self.record_frame();
+        Ok(())
+    }
+
+    fn record_frame(&mut self) {
+        let frame = self.frame();
+        let (id, len) = (frame.id(), frame.uncompressed_len());
+        self.stats.record_packet(id, len as usize);
+    }
+
+    /// The frame decoded by the last successful `advance`.
+    pub(crate) fn frame(&mut self) -> EncodedData<'_> {
+        EncodedData {
+            buf: &mut self.frame,
+            start: HEADER_SPACE,
+        }
+    }
+
+    pub fn shrink_to(&mut self, min_capacity: usize) {
+        self.frame.clear();
+        self.frame.shrink_to(min_capacity);
+        self.buf.truncate(self.filled.max(min_capacity));
+        self.buf.shrink_to(min_capacity);
+    }
+}
+
+/// The buffers of a `FrameDecoder` lent out to unpack a frame elsewhere.
+#[cfg(feature = "workpool")]
+pub(crate) struct Unpack {
+    buf: Vec<u8>,
+    range: std::ops::Range<usize>,
+    frame: Vec<u8>,
+    compression: Option<u32>,
+    limits: ReadLimits,
+    elapsed: Duration,
+}
+
+#[cfg(feature = "workpool")]
+impl Unpack {
+    /// Unpacks the frame using a fresh zlib stream.
+    pub(crate) fn run(&mut self) -> Result<(), ReadError> {
+        let start = Instant::now();
+        let res = unpack(
+            &self.buf[self.range.clone()],
+            &mut self.frame,
+            self.compression,
+            &self.limits,
+            &mut flate2::Decompress::new(true),
+        );
+        self.elapsed = start.elapsed();
+        res
+    }
+}
+
+/// Decompresses a frame if necessary and writes it to `out`,
+/// adhering to the encoding buffer structure.
+fn unpack(
+    data: &[u8],
+    out: &mut Vec<u8>,
+    compression: Option<u32>,
+    limits: &ReadLimits,
+    zlib: &mut flate2::Decompress,
+) -> Result<(), ReadError> {
+    out.clear();
+    // reserve the header space and push a zero-byte
+    // so we adhere to the encoding buffer structure
+    out.resize(HEADER_SPACE, 0);
+    out.push(0);
+
+    let threshold = match compression {
+        None => {
+            out.extend_from_slice(data);
+            return Ok(());
+        }
+        Some(threshold) => threshold,
+    };
+
+    let (uncompressed_len, header_len) = parse_varint(data)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "missing data length"))?;
+    let data = &data[header_len..];
+    let compressed_len = data.len() as u32;
+
+    if uncompressed_len == 0 {
+        if limits.validate_uncompressed && compressed_len >= threshold {
+            return Err(ReadError::AboveThreshold {
+                len: compressed_len,
+                threshold,
+            });
+        }
+        out.extend_from_slice(data);
+        return Ok(());
+    }
+
+    if uncompressed_len < threshold {
+        return Err(ReadError::BelowThreshold {
+            len: uncompressed_len,
+            threshold,
+        });
+    }
+    if uncompressed_len > limits.max_uncompressed_len {
+        return Err(ReadError::PacketTooLarge {
+            len: uncompressed_len,
+            max: limits.max_uncompressed_len,
+        });
+    }
+    if uncompressed_len / compressed_len.max(1) > limits.max_compression_ratio {
+        return Err(ReadError::RatioExceeded {
+            compressed: compressed_len,
+            uncompressed: uncompressed_len,
+        });
+    }
+
+    // `decompress_vec` only writes to the spare capacity, which bounds
+    // the output in case the peer lied about the uncompressed length
+    out.reserve_exact(uncompressed_len as usize);
+
+    let res = zlib.decompress_vec(data, out, flate2::FlushDecompress::Finish);
+    let actual = zlib.total_out();
+    zlib.reset(true);
+
+    match res? {
+        flate2::Status::StreamEnd if actual == uncompressed_len as u64 => Ok(()),
+        _ => Err(ReadError::LengthMismatch {
+            declared: uncompressed_len,
+            actual,
+        }),
+    }
+}
+
+/// Unpacks the frame of a prepared packet into a new encoding buffer.
+pub(crate) fn unpack_prepared(packet: &PreparedPacket) -> Result<Vec<u8>, ReadError> {
+    let frame = packet.frame();
+    let (_, header_len) = parse_varint(frame)?.ok_or(ReadError::VarIntTooLong)?;
+    let mut out = vec![];
+    unpack(
+        &frame[header_len..],
+        &mut out,
+        packet.compression(),
+        &ReadLimits::default(),
+        &mut flate2::Decompress::new(true),
+    )?;
+    Ok(out)
+}
+
+/// Parses a varint from the start of `buf`, returning its value and length
+/// or `None` if `buf` ends before the varint does.
+pub(crate) fn parse_varint(buf: &[u8]) -> Result<Option<(u32, usize)>, ReadError> {
+    let mut val = 0;
+    for (i, byte) in buf.iter().take(5).enumerate() {
+        val |= ((byte & 0x7f) as u32) << (i * 7);
+        if (byte & 0x80) == 0x00 {
+            return Ok(Some((val, i + 1)));
+        }
+    }
+    if buf.len() >= 5 {
+        Err(ReadError::VarIntTooLong)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Turns packets into bytes ready to be sent.
+pub struct FrameEncoder {
+    encryptor: Option<Option<Box<Encryptor>>>,
+    compressor: Option<Compressor>,
+    compress_capacity: usize,
+    stats: TrafficStats,
+    /// set while the compressor is lent out to compress elsewhere
+    #[cfg(feature = "workpool")]
+    busy: bool,
+}
+
+impl Default for FrameEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const DEFAULT_COMPRESS_BUF_CAPACITY: usize = 4096;
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_COMPRESS_BUF_CAPACITY)
+    }
+
+    /// Constructs a new `FrameEncoder` whose compression buffer
+    /// will be allocated with the given capacity.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            encryptor: None,
+            compressor: None,
+            compress_capacity: capacity,
+            stats: TrafficStats::default(),
+            #[cfg(feature = "workpool")]
+            busy: false,
+        }
+    }
+
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), InvalidLength> {
+        self.encryptor = Some(Some(Encryptor::new_from_slices(key, key)?.into()));
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &TrafficStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut TrafficStats {
+        &mut self.stats
+    }
+
+    /// Enables compression for packets with a length of at least `threshold`.
+    ///
+    /// A negative threshold disables compression, as with `SetCompression27`.
+    pub fn enable_compression(&mut self, threshold: i32) {
+        let threshold = match u32::try_from(threshold) {
+            Ok(threshold) => threshold,
+            Err(_) => {
+                self.compressor = None;
+                return;
+            }
+        };
+        match &mut self.compressor {
+            Some(compressor) => compressor.compression.threshold = threshold,
+            None => self.compressor = Some(Compressor::new(threshold, self.compress_capacity)),
+        }
+    }
+
+    /// Appends the frame for `encoded` to `out`, encrypting it if necessary.
+    ///
+    /// The frame is assembled and encrypted within the buffer of `encoded`,
+    /// prefer writing it from there using `frame` if possible.
+    pub fn encode(&mut self, encoded: EncodedData, out: &mut Vec<u8>) -> io::Result<()> {
+        let (buf, start) = self.frame(encoded)?;
+        out.extend_from_slice(&buf[start..]);
+        Ok(())
+    }
+
+    /// Turns `encoded` into an encrypted frame in place, returning
+    /// its buffer and the offset at which the frame starts.
+    pub fn frame<'a>(&mut self, encoded: EncodedData<'a>) -> io::Result<(&'a mut Vec<u8>, usize)> {
+        let packed = self.pack(encoded)?;
+        let (buf, start) = self.finish(packed);
+        self.encrypt(&mut buf[start..])?;
+        Ok((buf, start))
+    }
+
+    /// Appends the frame of a prepared packet to `out`, encrypting it if necessary.
+    pub fn encode_prepared(
+        &mut self,
+        packet: &PreparedPacket,
+        out: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        self.start_prepared(packet)?;
+        let start = out.len();
+        out.extend_from_slice(packet.frame());
+        self.encrypt(&mut out[start..])?;
+        Ok(())
+    }
+
+    /// Makes sure `packet` has been prepared for the compression
+    /// threshold in use and accounts it as written.
+    pub(crate) fn start_prepared(&mut self, packet: &PreparedPacket) -> io::Result<()> {
+        #[cfg(feature = "workpool")]
+        if self.busy {
+            return Err(AsyncCancelled.into());
+        }
+        let threshold = self
+            .compressor
+            .as_ref()
+            .map(|compressor| compressor.compression.threshold);
+        if packet.compression() != threshold {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "packet prepared for compression threshold {:?}, but the connection uses {:?}",
+                    packet.compression(),
+                    threshold
+                ),
+            ));
+        }
+        self.stats
+            .record_packet(packet.id(), packet.uncompressed_len() as usize);
+        self.stats.wire_bytes += packet.frame().len() as u64;
+        Ok(())
+    }
+
+    /// Whether frames are encrypted.
+    pub(crate) fn is_encrypted(&self) -> bool {
+        self.encryptor.is_some()
+    }
+
+    /// Compresses `encoded` if necessary, leaving space for the frame header.
+    pub(crate) fn pack<'a>(&mut self, encoded: EncodedData<'a>) -> io::Result<PackedData<'a>> {
+        #[cfg(feature = "workpool")]
+        if self.busy {
+            return Err(AsyncCancelled.into());
+        }
+        self.stats
+            .record_packet(encoded.id(), encoded.uncompressed_len() as usize);
+        let start = Instant::now();
+        let packed = encoded.pack(self.compressor.as_mut());
+        if self.compressor.is_some() {
+            self.stats.compression_time += start.elapsed();
+        }
+        packed
+    }
+
+    /// Writes the frame header of `packed`, returning the buffer
+    /// and the offset at which the unencrypted frame starts.
+    pub(crate) fn finish<'a>(&mut self, packed: PackedData<'a>) -> (&'a mut Vec<u8>, usize) {
+        let (buf, start) = packed.finish();
+        self.stats.wire_bytes += (buf.len() - start) as u64;
+        (buf, start)
+    }
+
+    /// Takes the compressor out of the encoder if `encoded` would
+    /// be compressed, so it can be compressed elsewhere.
+    ///
+    /// Until `finish_compression` is called, the encoder is unusable.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn take_compressor(
+        &mut self,
+        encoded: &EncodedData,
+    ) -> Result<Option<Compressor>, AsyncCancelled> {
+        if self.busy {
+            return Err(AsyncCancelled);
+        }
+        match &self.compressor {
+            Some(compressor) if encoded.uncompressed_len() >= compressor.compression.threshold => {
+                self.stats
+                    .record_packet(encoded.id(), encoded.uncompressed_len() as usize);
+                self.busy = true;
+                Ok(self.compressor.take())
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Restores the compressor, returning the packed data it has compressed
+    /// into `encoded`, which was `uncompressed_len` long and took `elapsed`.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn finish_compression<'a>(
+        &mut self,
+        compressor: Compressor,
+        res: io::Result<()>,
+        encoded: EncodedData<'a>,
+        uncompressed_len: u32,
+        elapsed: Duration,
+    ) -> io::Result<PackedData<'a>> {
+        self.stats.compression_time += elapsed;
+        self.compressor = Some(compressor);
+        self.busy = false;
+        res?;
+        Ok(PackedData::new(
+            encoded.buf,
+            HEADER_SPACE,
+            Some(uncompressed_len),
+        ))
+    }
+
+    pub(crate) fn encrypt(&mut self, data: &mut [u8]) -> Result<(), AsyncCancelled> {
+        if let Some(encryptor) = &mut self.encryptor {
+            let start = Instant::now();
+            encrypt(data, encryptor.as_mut().ok_or(AsyncCancelled)?);
+            self.stats.encryption_time += start.elapsed();
+        }
+        Ok(())
+    }
+
+    /// Takes the encryptor out of the encoder so data can be encrypted elsewhere.
+    ///
+    /// Until `restore_encryptor` is called, the encoder is unusable.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn take_encryptor(&mut self) -> Result<Option<Box<Encryptor>>, AsyncCancelled> {
+        match &mut self.encryptor {
+            None => Ok(None),
+            Some(encryptor) => encryptor.take().ok_or(AsyncCancelled).map(Some),
+        }
+    }
+
+    /// Restores the encryptor, which took `elapsed` to encrypt.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn restore_encryptor(&mut self, encryptor: Box<Encryptor>, elapsed: Duration) {
+        self.stats.encryption_time += elapsed;
+        self.encryptor = Some(Some(encryptor));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoder;
+    use miners_encoding::attrs::Rest;
+
+    fn roundtrip(threshold: i32, key: Option<&[u8]>, sizes: &[usize]) {
+        let mut encoder = FrameEncoder::new();
+        let mut decoder = FrameDecoder::new();
+        encoder.enable_compression(threshold);
+        decoder.enable_compression(threshold);
+        if let Some(key) = key {
+            encoder.enable_encryption(key).unwrap();
+            decoder.enable_encryption(key).unwrap();
+        }
+
+        let mut packet_encoder = Encoder::new();
+        let mut stream = vec![];
+        for (i, size) in sizes.iter().enumerate() {
+            let data = vec![i as u8; *size];
+            let encoded = packet_encoder
+                .encode(i as i32, Rest::from(&data[..]))
+                .unwrap();
+            encoder.encode(encoded, &mut stream).unwrap();
+        }
+
+        // feed in uneven chunks to make sure frames can span feeds
+        let mut decoded = 0;
+        for chunk in stream.chunks(7) {
+            decoder.feed(chunk).unwrap();
+            while let Some(frame) = decoder.decode().unwrap() {
+                let packet = frame.into_packet().unwrap();
+                assert_eq!(packet.id, decoded as i32);
+                assert_eq!(packet.data, &vec![decoded as u8; sizes[decoded]][..]);
+                decoded += 1;
+            }
+        }
+        assert_eq!(decoded, sizes.len());
+        assert_eq!(decoder.buffered(), 0);
+
+        let (sent, received) = (encoder.stats(), decoder.stats());
+        assert_eq!(sent.packets, sizes.len() as u64);
+        assert_eq!(sent.wire_bytes, stream.len() as u64);
+        assert_eq!(sent.packets, received.packets);
+        assert_eq!(sent.packets_by_id, received.packets_by_id);
+        assert_eq!(sent.wire_bytes, received.wire_bytes);
+        assert_eq!(sent.uncompressed_bytes, received.uncompressed_bytes);
+    }
+
+    #[test]
+    fn plain() {
+        roundtrip(-1, None, &[0, 1, 300, 70000]);
+    }
+
+    #[test]
+    fn compressed() {
+        roundtrip(256, None, &[0, 1, 255, 256, 300, 70000]);
+    }
+
+    #[test]
+    fn encrypted() {
+        roundtrip(256, Some(&[7; 16]), &[0, 1, 255, 256, 300, 70000]);
+    }
+
+    #[cfg(feature = "workpool")]
+    #[test]
+    fn encryption_after_cancelled_read() {
+        let mut decoder = FrameDecoder::new();
+        decoder.enable_encryption(&[1; 16]).unwrap();
+        decoder.feed(&[1, 2, 3]).unwrap();
+        // a read offloading the decryption is dropped before it finishes
+        let _ = decoder.take_decryption().unwrap();
+        assert!(matches!(
+            decoder.enable_encryption(&[2; 16]),
+            Err(EncryptionError::Cancelled)
+        ));
+    }
+
+    #[cfg(feature = "workpool")]
+    #[test]
+    fn legacy_ping_after_cancelled_read() {
+        let mut decoder = FrameDecoder::new();
+        decoder.enable_encryption(&[1; 16]).unwrap();
+        decoder.feed(&[0xfe, 0x01]).unwrap();
+        let _ = decoder.take_decryption().unwrap();
+        assert!(matches!(decoder.is_legacy_ping(), Err(ReadError::Io(_))));
+    }
+
+    #[test]
+    fn reencode() {
+        let mut encoder = FrameEncoder::new();
+        encoder.enable_compression(256);
+        let mut packet_encoder = Encoder::new();
+        let mut stream = vec![];
+        for size in [0, 300] {
+            let data = vec![1; size];
+            let encoded = packet_encoder.encode(1, Rest::from(&data[..])).unwrap();
+            encoder.encode(encoded, &mut stream).unwrap();
+        }
+        let mut raw = vec![0, 2, 3];
+        // SAFETY: the vector holds a marker, an id and data
+        let raw = unsafe { EncodedData::from_raw(&mut raw) };
+        encoder.encode(raw, &mut stream).unwrap();
+
+        // decoded frames keep the header space, so they can be sent on as they are
+        let mut decoder = FrameDecoder::new();
+        decoder.enable_compression(256);
+        decoder.feed(&stream).unwrap();
+        let mut forwarded = vec![];
+        while let Some(frame) = decoder.decode().unwrap() {
+            encoder.encode(frame, &mut forwarded).unwrap();
+        }
+        assert_eq!(forwarded, stream);
+    }
+
+    #[test]
+    fn prepared() {
+        let data = vec![9; 1000];
+        let mut packet_encoder = Encoder::new();
+        let encoded = packet_encoder.encode(3, Rest::from(&data[..])).unwrap();
+        let packet = PreparedPacket::new(encoded, 256).unwrap();
+
+        for key in [[1; 16], [2; 16]] {
+            let mut encoder = FrameEncoder::new();
+            let mut decoder = FrameDecoder::new();
+            encoder.enable_compression(256);
+            decoder.enable_compression(256);
+            encoder.enable_encryption(&key).unwrap();
+            decoder.enable_encryption(&key).unwrap();
+
+            let mut stream = vec![];
+            encoder.encode_prepared(&packet, &mut stream).unwrap();
+            encoder
+                .encode_prepared(&packet.clone(), &mut stream)
+                .unwrap();
+            decoder.feed(&stream).unwrap();
+            for _ in 0..2 {
+                let frame = decoder.decode().unwrap().unwrap();
+                let decoded = frame.into_packet().unwrap();
+                assert_eq!(decoded.id, 3);
+                assert_eq!(decoded.data, &data[..]);
+            }
+        }
+
+        let mut encoder = FrameEncoder::new();
+        encoder.enable_compression(64);
+        assert!(encoder.encode_prepared(&packet, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&[0xff; 6]).unwrap();
+        assert!(matches!(decoder.decode(), Err(ReadError::VarIntTooLong)));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&[0x80, 0x80, 0x80, 0x01]).unwrap();
+        assert!(matches!(
+            decoder.decode(),
+            Err(ReadError::FrameTooLarge { .. })
+        ));
+
+        // a "compressed" frame below the threshold
+        let mut decoder = FrameDecoder::new();
+        decoder.enable_compression(256);
+        decoder.feed(&[3, 10, 0, 0]).unwrap();
+        assert!(matches!(
+            decoder.decode(),
+            Err(ReadError::BelowThreshold { len: 10, .. })
+        ));
+
+        // an uncompressed frame at the threshold, rejected by default
+        assert!(ReadLimits::default().validate_uncompressed);
+        let mut decoder = FrameDecoder::new();
+        decoder.enable_compression(4);
+        decoder.feed(&[6, 0, 1, 2, 3, 4, 5]).unwrap();
+        assert!(matches!(
+            decoder.decode(),
+            Err(ReadError::AboveThreshold {
+                len: 5,
+                threshold: 4
+            })
+        ));
+        decoder.set_limits(ReadLimits {
+            validate_uncompressed: false,
+            ..ReadLimits::default()
+        });
+        decoder.feed(&[6, 0, 1, 2, 3, 4, 5]).unwrap();
+        assert!(decoder.decode().unwrap().is_some());
+
+        // 3 bytes claiming to inflate to 8 MiB
+        let mut decoder = FrameDecoder::new();
+        decoder.enable_compression(256);
+        decoder.feed(&[7, 0x80, 0x80, 0x80, 0x04, 0, 0, 0]).unwrap();
+        assert!(matches!(
+            decoder.decode(),
+            Err(ReadError::RatioExceeded { .. })
+        ));
+    }
+}
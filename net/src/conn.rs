@@ -1,14 +1,18 @@
-use aes::cipher::{InvalidLength, KeyIvInit};
+use miners_packet::Direction;
+
+use crate::capture::Capture;
 use futures_lite::io::{AsyncRead, AsyncWrite};
 use futures_lite::io::{BufReader, BufWriter};
+pub mod queue;
 mod readhalf;
+#[cfg(feature = "tokio")]
+mod tokio_compat;
 mod writehalf;
+pub use crate::codec::{EncryptionError, ReadError, ReadLimits};
 pub use readhalf::ReadHalf;
 // use writehalf::Compression;
 pub use writehalf::WriteHalf;
 
-const INITIAL_BUF_SIZE: usize = 1024;
-
 /// A united connection.
 /// After compression and encryption are set, `Connection` should be split into `ReadHalf` and `WriteHalf`.
 pub struct Connection<R, W> {
@@ -33,21 +37,38 @@ impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Connection<R, W> {
         (self.read_half, self.write_half)
     }
 
-    // pub fn set_compression(&mut self, threshold: i32, compression: flate2::Compression) {
-    //     self.write_half
-    //         .compression
-    //         .set_compression(threshold, compression);
-    //     self.read_half.compression = Some(Vec::with_capacity(INITIAL_BUF_SIZE));
-    // }
+    /// Enables compression on both halves, as requested by `SetCompression27`.
+    ///
+    /// Packets with a length of at least `threshold` will be compressed,
+    /// a negative threshold disables compression.
+    pub fn enable_compression(&mut self, threshold: i32) {
+        self.read_half.enable_compression(threshold);
+        self.write_half.enable_compression(threshold);
+    }
+
+    #[cfg(feature = "workpool")]
+    /// Attaches both halves to `workpool` instead of the default pool.
+    pub fn set_workpool(&mut self, workpool: crate::workpool::WorkPool) {
+        self.read_half.set_workpool(workpool.clone());
+        self.write_half.set_workpool(workpool);
+    }
 
-    pub fn enable_encryption(
-        &mut self,
-        key: &[u8],
-    ) -> Result<(), InvalidLength> {
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), EncryptionError> {
         self.read_half.enable_encryption(key)?;
-        self.write_half
-            .enable_encryption(cfb8::Encryptor::new_from_slices(key, key)?);
-        Ok(())
+        Ok(self.write_half.enable_encryption(key)?)
+    }
+
+    /// The address of the client as sent by a load balancer in the PROXY protocol
+    /// header, `None` if no header has been read or the address is unknown.
+    pub fn source_addr(&self) -> Option<std::net::SocketAddr> {
+        self.read_half.proxy_header()?.source()
+    }
+
+    /// Records the packets of both halves to `capture`,
+    /// with `incoming` being the direction of packets read.
+    pub fn set_capture(&mut self, capture: Capture, incoming: Direction) {
+        self.read_half.set_capture(capture.clone(), incoming);
+        self.write_half.set_capture(capture, incoming.opposite());
     }
 }
 
@@ -70,3 +91,41 @@ impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Connection<R, W> {
 //         Connection::new(v.0, v.1)
 //     }
 // }
+
+#[cfg(all(test, feature = "workpool"))]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoder;
+    use futures_lite::future::block_on;
+    use miners_encoding::attrs::Rest;
+
+    #[test]
+    fn offloaded() {
+        let mut write_half = WriteHalf::new(vec![]);
+        write_half.enable_compression(64);
+        write_half.enable_encryption(&[3; 16]).unwrap();
+        write_half.set_blocking_threshold(0);
+        write_half.set_compression_blocking_threshold(0);
+
+        let mut encoder = Encoder::new();
+        let sizes = [0, 63, 64, 5000, 300000];
+        for size in sizes {
+            let data = vec![size as u8; size];
+            let encoded = encoder.encode(0x26, Rest::from(&data[..])).unwrap();
+            block_on(write_half.write(encoded)).unwrap();
+        }
+
+        let written = write_half.writer;
+        let mut read_half = ReadHalf::new(&written[..]);
+        read_half.enable_compression(64);
+        read_half.enable_encryption(&[3; 16]).unwrap();
+        read_half.set_blocking_threshold(0);
+        read_half.set_compression_blocking_threshold(0);
+        for size in sizes {
+            let encoded = block_on(read_half.read_encoded()).unwrap();
+            let packet = encoded.into_packet().unwrap();
+            assert_eq!(packet.id, 0x26);
+            assert_eq!(packet.data, &vec![size as u8; size][..]);
+        }
+    }
+}
@@ -0,0 +1,469 @@
+//! A write queue which lets many tasks send packets over one `WriteHalf`.
+//!
+//! Packets are queued using a cloneable `WriteQueue` and written by a
+//! `QueueDriver`, which has to be spawned on the executor of choice.
+//! The driver writes higher priorities first and only flushes once the
+//! queues run empty, coalescing everything queued in the meantime.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+
+use futures_lite::AsyncWrite;
+use miners_encoding::{encode, Encode};
+
+use super::WriteHalf;
+use crate::encoding::{EncodedData, Encoder};
+use crate::packing::PreparedPacket;
+
+/// The priority class of a queued packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Written before anything else, like `KeepAlive` or `Disconnect`.
+    High,
+    Normal,
+    /// Bulk data like chunks, written once nothing else is queued.
+    Bulk,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What to do when sending to a priority class which is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait until the driver has written enough packets.
+    Wait,
+    /// Drop the packet being sent.
+    DropNewest,
+    /// Drop the oldest queued packet of the same priority.
+    DropOldest,
+}
+
+/// The configuration of a write queue.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueConfig {
+    /// The maximum amount of packets queued per priority class, at least one.
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            overflow: Overflow::Wait,
+        }
+    }
+}
+
+/// The error returned when sending to a queue fails.
+#[derive(Debug, thiserror::Error)]
+pub enum QueueError {
+    #[error("the queue driver has stopped")]
+    Closed(QueuedPacket),
+    #[error("the queue is full")]
+    Full(QueuedPacket),
+}
+
+impl QueueError {
+    /// Returns the packet which could not be sent.
+    pub fn into_inner(self) -> QueuedPacket {
+        match self {
+            QueueError::Closed(packet) | QueueError::Full(packet) => packet,
+        }
+    }
+}
+
+/// An owned packet waiting in a write queue.
+#[derive(Debug)]
+pub struct QueuedPacket(Inner);
+
+enum Inner {
+    /// an encoding buffer with the marker at `start`
+    Encoded {
+        buf: Vec<u8>,
+        start: usize,
+    },
+    Prepared(PreparedPacket),
+}
+
+impl std::fmt::Debug for Inner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Inner::Encoded { buf, start } => f
+                .debug_struct("Encoded")
+                .field("len", &(buf.len() - start))
+                .finish(),
+            Inner::Prepared(packet) => f
+                .debug_struct("Prepared")
+                .field("len", &packet.frame().len())
+                .finish(),
+        }
+    }
+}
+
+impl QueuedPacket {
+    pub fn encode(id: i32, data: impl Encode) -> encode::Result<Self> {
+        let mut encoder = Encoder::new();
+        Ok(encoder.encode(id, data)?.into())
+    }
+
+    pub fn from_packet<P>(
+        version: miners_version::ProtocolVersion,
+        packet: P,
+    ) -> Option<encode::Result<Self>>
+    where
+        P: miners_packet::Packet,
+    {
+        let mut encoder = Encoder::new();
+        Some(encoder.encode_packet(version, packet)?.map(Into::into))
+    }
+}
+
+impl From<EncodedData<'_>> for QueuedPacket {
+    /// Takes the buffer of the encoded data, the `Encoder` allocates a new one.
+    fn from(encoded: EncodedData<'_>) -> Self {
+        QueuedPacket(Inner::Encoded {
+            buf: std::mem::take(encoded.buf),
+            start: encoded.start,
+        })
+    }
+}
+
+impl From<PreparedPacket> for QueuedPacket {
+    fn from(packet: PreparedPacket) -> Self {
+        QueuedPacket(Inner::Prepared(packet))
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+}
+
+struct State {
+    queues: [VecDeque<QueuedPacket>; Priority::COUNT],
+    config: QueueConfig,
+    /// the amount of `WriteQueue` handles alive
+    senders: usize,
+    /// set once the driver has stopped
+    closed: bool,
+    dropped: u64,
+    driver: Option<Waker>,
+    /// senders waiting for space, per priority class
+    waiting: [Vec<Waker>; Priority::COUNT],
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn poll_pop(&self, cx: Option<&mut Context<'_>>) -> Poll<Option<QueuedPacket>> {
+        let mut state = self.lock();
+        for i in 0..Priority::COUNT {
+            if let Some(packet) = state.queues[i].pop_front() {
+                state.waiting[i].drain(..).for_each(Waker::wake);
+                return Poll::Ready(Some(packet));
+            }
+        }
+        if state.senders == 0 {
+            return Poll::Ready(None);
+        }
+        if let Some(cx) = cx {
+            state.driver = Some(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// A handle to send packets to a write queue, cloning it is cheap.
+///
+/// The driver stops once all handles have been dropped.
+pub struct WriteQueue {
+    shared: Arc<Shared>,
+}
+
+impl Clone for WriteQueue {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        WriteQueue {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for WriteQueue {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.driver.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl WriteQueue {
+    /// Queues a packet, waiting for space if the queue is full and
+    /// configured to do so. Other overflow policies never wait.
+    pub async fn send(
+        &self,
+        packet: impl Into<QueuedPacket>,
+        priority: Priority,
+    ) -> Result<(), QueueError> {
+        let mut packet = Some(packet.into());
+        std::future::poll_fn(|cx| {
+            match self.push(packet.take().expect("polled after completion"), priority) {
+                Err(QueueError::Full(full)) => {
+                    self.shared.lock().waiting[priority.index()].push(cx.waker().clone());
+                    // the driver might have made space in the meantime
+                    match self.push(full, priority) {
+                        Err(QueueError::Full(full)) => {
+                            packet = Some(full);
+                            Poll::Pending
+                        }
+                        res => Poll::Ready(res),
+                    }
+                }
+                res => Poll::Ready(res),
+            }
+        })
+        .await
+    }
+
+    /// Queues a packet without waiting, returning it if the queue
+    /// is full and configured to wait for space.
+    pub fn try_send(
+        &self,
+        packet: impl Into<QueuedPacket>,
+        priority: Priority,
+    ) -> Result<(), QueueError> {
+        self.push(packet.into(), priority)
+    }
+
+    /// The amount of packets dropped because a priority class was full.
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+
+    /// The amount of packets currently queued.
+    pub fn len(&self) -> usize {
+        self.shared.lock().queues.iter().map(VecDeque::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn push(&self, packet: QueuedPacket, priority: Priority) -> Result<(), QueueError> {
+        let mut state = self.shared.lock();
+        if state.closed {
+            return Err(QueueError::Closed(packet));
+        }
+        let capacity = state.config.capacity;
+        if state.queues[priority.index()].len() >= capacity {
+            match state.config.overflow {
+                Overflow::Wait => return Err(QueueError::Full(packet)),
+                Overflow::DropNewest => {
+                    state.dropped += 1;
+                    return Ok(());
+                }
+                Overflow::DropOldest => {
+                    state.queues[priority.index()].pop_front();
+                    state.dropped += 1;
+                }
+            }
+        }
+        state.queues[priority.index()].push_back(packet);
+        if let Some(waker) = state.driver.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+/// Writes the packets of a write queue to a `WriteHalf`.
+pub struct QueueDriver<W> {
+    write_half: WriteHalf<W>,
+    closer: Closer,
+}
+
+/// Closes the queue once the driver is dropped, even if it never ran.
+struct Closer(Arc<Shared>);
+
+impl Drop for Closer {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.closed = true;
+        state.queues.iter_mut().for_each(VecDeque::clear);
+        for waiting in &mut state.waiting {
+            waiting.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+/// Creates a write queue for `write_half`, panics if the capacity is zero.
+pub fn queue<W>(write_half: WriteHalf<W>, config: QueueConfig) -> (WriteQueue, QueueDriver<W>) {
+    assert!(config.capacity > 0, "a write queue needs a capacity");
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queues: Default::default(),
+            config,
+            senders: 1,
+            closed: false,
+            dropped: 0,
+            driver: None,
+            waiting: Default::default(),
+        }),
+    });
+    let driver = QueueDriver {
+        write_half,
+        closer: Closer(shared.clone()),
+    };
+    (WriteQueue { shared }, driver)
+}
+
+impl<W> QueueDriver<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    /// Writes queued packets until all `WriteQueue`s have been dropped
+    /// and the queues are empty, returning the `WriteHalf` afterwards.
+    ///
+    /// The writer is flushed whenever the queues run empty. If writing
+    /// fails, the queue is closed and the error returned.
+    pub async fn run(mut self) -> io::Result<WriteHalf<W>> {
+        loop {
+            let packet = match self.closer.0.poll_pop(None) {
+                Poll::Ready(Some(packet)) => packet,
+                Poll::Ready(None) => break,
+                Poll::Pending => {
+                    self.write_half.flush().await?;
+                    match Pop(&self.closer.0).await {
+                        Some(packet) => packet,
+                        None => break,
+                    }
+                }
+            };
+            match packet.0 {
+                Inner::Encoded { mut buf, start } => {
+                    let encoded = EncodedData {
+                        buf: &mut buf,
+                        start,
+                    };
+                    self.write_half.write(encoded).await?
+                }
+                Inner::Prepared(packet) => self.write_half.write_prepared(&packet).await?,
+            }
+        }
+        self.write_half.flush().await?;
+        Ok(self.write_half)
+    }
+}
+
+struct Pop<'a>(&'a Shared);
+
+impl Future for Pop<'_> {
+    type Output = Option<QueuedPacket>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.0.poll_pop(Some(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::ReadHalf;
+    use futures_lite::future::{block_on, poll_once, zip};
+    use miners_encoding::attrs::Rest;
+
+    #[test]
+    fn priorities_and_overflow() {
+        let (queue, driver) = queue(
+            WriteHalf::new(vec![]),
+            QueueConfig {
+                capacity: 2,
+                overflow: Overflow::DropOldest,
+            },
+        );
+        let packet = |id: i32| QueuedPacket::encode(id, Rest::from(&[id as u8][..])).unwrap();
+        for id in 0..3 {
+            queue.try_send(packet(id), Priority::Bulk).unwrap();
+        }
+        queue.try_send(packet(3), Priority::Normal).unwrap();
+        queue.try_send(packet(4), Priority::High).unwrap();
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.len(), 4);
+        drop(queue);
+
+        let written = block_on(driver.run()).unwrap().writer;
+        let mut read_half = ReadHalf::new(&written[..]);
+        for id in [4, 3, 1, 2] {
+            let packet = block_on(read_half.read_encoded()).unwrap();
+            assert_eq!(packet.into_packet().unwrap().id, id);
+        }
+    }
+
+    #[test]
+    #[should_panic = "a write queue needs a capacity"]
+    fn zero_capacity() {
+        let config = QueueConfig {
+            capacity: 0,
+            overflow: Overflow::DropOldest,
+        };
+        queue(WriteHalf::new(Vec::<u8>::new()), config);
+    }
+
+    #[test]
+    fn wait_for_space() {
+        let (queue, driver) = queue(
+            WriteHalf::new(vec![]),
+            QueueConfig {
+                capacity: 1,
+                overflow: Overflow::Wait,
+            },
+        );
+        let packet = |id: i32| QueuedPacket::encode(id, Rest::from(&[id as u8][..])).unwrap();
+        queue.try_send(packet(0), Priority::Normal).unwrap();
+        assert!(matches!(
+            queue.try_send(packet(1), Priority::Normal),
+            Err(QueueError::Full(_))
+        ));
+
+        let mut sender = Box::pin(async move {
+            queue.send(packet(1), Priority::Normal).await.unwrap();
+        });
+        // the queue is full until the driver writes the first packet
+        assert!(block_on(poll_once(&mut sender)).is_none());
+        let (_, driven) = block_on(zip(sender, driver.run()));
+
+        let written = driven.unwrap().writer;
+        let mut read_half = ReadHalf::new(&written[..]);
+        for id in [0, 1] {
+            let packet = block_on(read_half.read_encoded()).unwrap();
+            assert_eq!(packet.into_packet().unwrap().id, id);
+        }
+    }
+
+    #[test]
+    fn closed() {
+        let (queue, driver) = queue(WriteHalf::new(Vec::<u8>::new()), QueueConfig::default());
+        drop(driver);
+        let packet = QueuedPacket::encode(0, Rest::from(&[][..])).unwrap();
+        assert!(matches!(
+            queue.try_send(packet, Priority::High),
+            Err(QueueError::Closed(_))
+        ));
+    }
+}
@@ -1,155 +1,111 @@
-use std::pin::Pin;
-use std::task::Poll;
-use std::{fmt::Display, io};
-
-use crate::encoding::EncodedData;
-use crate::helpers::{encrypt, AsyncCancelled};
-#[cfg(feature = "workpool")]
-use crate::DEFAULT_UNBLOCK_THRESHOLD;
-
-use super::INITIAL_BUF_SIZE;
-
-use aes::cipher::{InvalidLength, KeyIvInit};
-use futures_lite::ready;
 use futures_lite::{AsyncRead, AsyncReadExt};
 
-/// The maximum packet length, 8 MiB
-const MAX_PACKET_LENGTH: u32 = 1024 * 1024 * 8;
-
-#[inline]
-fn verify_len(len: u32) -> std::io::Result<()> {
-    if len > MAX_PACKET_LENGTH {
-        Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            format!(
-                "The data length exceeds the maximum packet length! {len} > {MAX_PACKET_LENGTH}"
-            ),
-        ))
-    } else {
-        Ok(())
-    }
-}
+use miners_packet::Direction;
 
-// const AVG_PACKET_THRESHOLD: usize = 65536;
+use crate::capture::Capture;
+use crate::codec::{EncryptionError, FrameDecoder, ReadError, ReadLimits};
+use crate::encoding::EncodedData;
+use crate::legacy::{LegacyPing, LegacyStatus};
+use crate::proxy_protocol::ProxyHeader;
+use crate::stats::TrafficStats;
+#[cfg(feature = "workpool")]
+use crate::{workpool::WorkPool, DEFAULT_COMPRESSION_UNBLOCK_THRESHOLD, DEFAULT_UNBLOCK_THRESHOLD};
 
 /// The reading half of a connection.
 /// Returned from `Connection::split()`
 pub struct ReadHalf<R> {
-    pub(super) compression: Option<Vec<u8>>,
-    zlib: flate2::Decompress,
-    readbuf: Vec<u8>,
-    reader: Reader<R>,
-}
-
-pub struct Reader<R> {
+    decoder: FrameDecoder,
     reader: R,
-    decryptor: Option<Option<Box<cfb8::Encryptor<aes::Aes128>>>>,
+    capture: Option<(Capture, Direction)>,
+    proxy_header: Option<ProxyHeader>,
     #[cfg(feature = "workpool")]
     unblock_threshold: u32,
+    #[cfg(feature = "workpool")]
+    compression_unblock_threshold: u32,
+    #[cfg(feature = "workpool")]
+    workpool: Option<WorkPool>,
 }
 
-impl<R> Reader<R>
-where
-    R: AsyncRead + Unpin,
-{
-    async fn read(&mut self, buf: &mut Vec<u8>, len: u32) -> io::Result<()> {
-        // TODO: replace with readbuf or similar api once that is stabilized
-        buf.resize(buf.len() + len as usize, 0);
-        let slice_start = buf.len() - len as usize;
-        self.reader.read_exact(&mut buf[slice_start..]).await?;
-        if let Some(decryptor) = &mut self.decryptor {
-            let mut decryptor = decryptor.take().ok_or(AsyncCancelled)?;
+impl<R> ReadHalf<R> {
+    pub(super) fn new(reader: R) -> Self {
+        Self {
+            decoder: FrameDecoder::new(),
+            reader,
+            capture: None,
+            proxy_header: None,
+            #[cfg(feature = "workpool")]
+            unblock_threshold: DEFAULT_UNBLOCK_THRESHOLD,
             #[cfg(feature = "workpool")]
-            let decryptor = if len > self.unblock_threshold {
-                let taken_buf = std::mem::take(buf);
-                // SAFETY: this is safe as we are specifying a length that was just written
-                let (taken_buf, mutated_decryptor) = unsafe {
-                    crate::workpool::request_partial_encryption(taken_buf, len as usize, decryptor)
-                        .await
-                        .await
-                        .unwrap()
-                };
-                *buf = taken_buf;
-                mutated_decryptor
-            } else {
-                encrypt(&mut buf[slice_start..], &mut decryptor);
-                decryptor
-            };
-            #[cfg(not(feature = "workpool"))]
-            encrypt(&mut buf[slice_start..], &mut decryptor);
-            self.decryptor = Some(Some(decryptor));
+            compression_unblock_threshold: DEFAULT_COMPRESSION_UNBLOCK_THRESHOLD,
+            #[cfg(feature = "workpool")]
+            workpool: None,
         }
-        Ok(())
     }
-}
 
-impl<R: AsyncRead + Unpin> AsyncRead for Reader<R> {
-    fn poll_read(
-        self: Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut [u8],
-    ) -> std::task::Poll<io::Result<usize>> {
-        let this = self.get_mut();
-        match &mut this.decryptor {
-            None => Pin::new(&mut this.reader).poll_read(cx, buf),
-            Some(decryptor) => {
-                let mut decryptor = decryptor.take().ok_or(AsyncCancelled)?;
-                let n = ready!(Pin::new(&mut this.reader).poll_read(cx, buf))?;
-                encrypt(buf, &mut decryptor);
-                this.decryptor = Some(Some(decryptor));
-                Poll::Ready(Ok(n))
-            }
-        }
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), EncryptionError> {
+        self.decoder.enable_encryption(key)
     }
-}
 
-#[derive(Debug)]
-struct PacketLengthTooLarge;
-impl std::error::Error for PacketLengthTooLarge {}
-impl Display for PacketLengthTooLarge {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("packet length too large")
+    /// Enables decompression of incoming packets.
+    ///
+    /// A negative threshold disables compression, as with `SetCompression27`.
+    pub fn enable_compression(&mut self, threshold: i32) {
+        self.decoder.enable_compression(threshold)
     }
-}
 
-impl<R> ReadHalf<R> {
-    pub(super) fn new(reader: R) -> Self {
-        Self {
-            compression: None,
-            zlib: flate2::Decompress::new(true),
-            readbuf: Vec::with_capacity(INITIAL_BUF_SIZE),
-            reader: Reader {
-                reader,
-                decryptor: None,
-                #[cfg(feature = "workpool")]
-                unblock_threshold: DEFAULT_UNBLOCK_THRESHOLD,
-            },
-        }
+    pub fn stats(&self) -> &TrafficStats {
+        self.decoder.stats()
     }
 
-    pub(super) fn enable_encryption(&mut self, key: &[u8]) -> Result<(), InvalidLength> {
-        self.reader.decryptor = Some(Some(cfb8::Encryptor::new_from_slices(key, key)?.into()));
-        Ok(())
+    pub fn stats_mut(&mut self) -> &mut TrafficStats {
+        self.decoder.stats_mut()
+    }
+
+    pub fn limits(&self) -> &ReadLimits {
+        self.decoder.limits()
     }
 
-    pub(super) fn enable_compression(&mut self) {
-        self.compression = Some(Vec::with_capacity(super::INITIAL_BUF_SIZE))
+    pub fn set_limits(&mut self, limits: ReadLimits) {
+        self.decoder.set_limits(limits)
     }
 
     #[cfg(feature = "workpool")]
     /// sets the threshold which determines if to offload
     /// packet decryption using cfb8/aes128 to the workpool
     pub fn set_blocking_threshold(&mut self, threshold: u32) {
-        self.reader.unblock_threshold = threshold;
+        self.unblock_threshold = threshold;
+    }
+
+    #[cfg(feature = "workpool")]
+    /// sets the threshold which determines if to offload
+    /// packet decompression using zlib to the workpool
+    pub fn set_compression_blocking_threshold(&mut self, threshold: u32) {
+        self.compression_unblock_threshold = threshold;
+    }
+
+    #[cfg(feature = "workpool")]
+    /// sets the pool work is offloaded to instead of the default one
+    pub fn set_workpool(&mut self, workpool: WorkPool) {
+        self.workpool = Some(workpool);
+    }
+
+    /// Records every packet read to `capture`, which are sent in `direction`.
+    pub fn set_capture(&mut self, capture: Capture, direction: Direction) {
+        self.capture = Some((capture, direction));
+    }
+
+    /// The capture recording the packets read, if any.
+    pub fn capture(&self) -> Option<&Capture> {
+        self.capture.as_ref().map(|(capture, _)| capture)
+    }
+
+    /// The PROXY protocol header read by `read_proxy_header`.
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy_header.as_ref()
     }
 
     pub fn shrink_to(&mut self, min_capacity: usize) {
-        self.readbuf.clear();
-        self.readbuf.shrink_to(min_capacity);
-        if let Some(comp_buf) = &mut self.compression {
-            comp_buf.clear();
-            comp_buf.shrink_to(min_capacity);
-        }
+        self.decoder.shrink_to(min_capacity)
     }
 }
 
@@ -157,76 +113,116 @@ impl<R> ReadHalf<R>
 where
     R: AsyncRead + Unpin,
 {
-    pub async fn read_encoded(&mut self) -> io::Result<EncodedData> {
-        self.readbuf.clear();
-        if self.compression.is_none() {
-            // push a zero-byte so we adhere to the encoding buffer structure
-            self.readbuf.push(0);
-        }
-        let len = read_varint_async(&mut self.reader).await?;
-        self.reader.read(&mut self.readbuf, len).await?;
-        match &mut self.compression {
-            None => Ok(EncodedData(&mut self.readbuf)),
-            Some(_) if self.readbuf[0] == 0 => {
-                // compression enabled, prefixed with zero-byte
-                Ok(EncodedData(&mut self.readbuf))
+    /// Reads the next packet.
+    ///
+    /// Every read pulls in as many bytes as are available, so following
+    /// packets are returned from the buffer without touching the reader.
+    pub async fn read_encoded(&mut self) -> Result<EncodedData<'_>, ReadError> {
+        loop {
+            if let Some(range) = self.decoder.next_frame()? {
+                self.unpack(range).await?;
+                let frame = self.decoder.frame();
+                if let Some((capture, direction)) = &self.capture {
+                    capture.record(*direction, &frame);
+                }
+                return Ok(frame);
             }
-            Some(compression_buf) => {
-                compression_buf.clear();
-                compression_buf.push(0);
-                let mut reader = std::io::Cursor::new(&self.readbuf[..]);
-
-                let uncompressed_len = read_varint(&mut reader)?;
+            let wanted = self.decoder.wanted();
+            self.fill_wanted(wanted).await?;
+        }
+    }
 
-                verify_len(uncompressed_len)?;
+    /// Checks whether the client opened the connection with a legacy ping,
+    /// which has to be done before reading the first packet.
+    ///
+    /// Returns `None` for netty clients, whose handshake is then returned
+    /// by `read_encoded` as usual.
+    pub async fn read_legacy_ping(&mut self) -> Result<Option<LegacyPing>, ReadError> {
+        loop {
+            match self.decoder.is_legacy_ping()? {
+                Some(false) => return Ok(None),
+                Some(true) => {
+                    if let Some(ping) = self.decoder.decode_legacy_ping()? {
+                        return Ok(Some(ping));
+                    }
+                }
+                None => {}
+            }
+            self.fill().await?;
+        }
+    }
 
-                compression_buf.reserve_exact(uncompressed_len as usize);
+    /// Reads the PROXY protocol header a load balancer sends before the first packet.
+    ///
+    /// Fails if the connection doesn't start with one, so this should only be
+    /// called on connections known to come from a balancer.
+    pub async fn read_proxy_header(&mut self) -> Result<&ProxyHeader, ReadError> {
+        loop {
+            if let Some(header) = self.decoder.decode_proxy_header()? {
+                return Ok(self.proxy_header.insert(header));
+            }
+            self.fill().await?;
+        }
+    }
 
-                // error check?
-                self.zlib
-                    .decompress_vec(
-                        &reader.get_ref()[reader.get_ref().len()..],
-                        compression_buf,
-                        flate2::FlushDecompress::Finish,
-                    )
-                    .ok();
+    /// Reads the answer to a legacy ping.
+    pub async fn read_legacy_status(&mut self) -> Result<LegacyStatus, ReadError> {
+        loop {
+            if let Some(status) = self.decoder.decode_legacy_status()? {
+                return Ok(status);
+            }
+            self.fill().await?;
+        }
+    }
 
-                self.zlib.reset(true);
+    async fn fill(&mut self) -> Result<(), ReadError> {
+        self.fill_wanted(1).await
+    }
 
-                Ok(EncodedData(compression_buf))
-            }
+    /// Reads whatever is available into the decoder, offering at least `wanted` bytes of space.
+    async fn fill_wanted(&mut self, wanted: usize) -> Result<(), ReadError> {
+        let read = self.reader.read(self.decoder.read_buf(wanted)).await?;
+        if read == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
         }
+        self.decoder.commit(read);
+        self.decrypt().await
     }
-}
 
-fn read_varint<R>(reader: &mut R) -> io::Result<u32>
-where
-    R: io::Read,
-{
-    let mut val = 0;
-    let mut cur_val = [0];
-    for i in 0..5 {
-        reader.read_exact(&mut cur_val)?;
-        val += ((cur_val[0] & 0x7f) as u32) << (i * 7);
-        if (cur_val[0] & 0x80) == 0x00 {
-            break;
+    async fn unpack(&mut self, range: std::ops::Range<usize>) -> Result<(), ReadError> {
+        #[cfg(feature = "workpool")]
+        if self.decoder.declared_len(range.clone()) >= self.compression_unblock_threshold {
+            let mut unpack = self.decoder.take_unpack(range);
+            let workpool = self.workpool.clone().unwrap_or_default();
+            let (unpack, res) = workpool
+                .run(move || {
+                    let res = unpack.run();
+                    (unpack, res)
+                })
+                .await
+                .map_err(std::io::Error::from)?;
+            return self.decoder.finish_unpack(unpack, res);
         }
+        self.decoder.unpack(range)
     }
-    Ok(val)
-}
 
-async fn read_varint_async<R>(reader: &mut R) -> io::Result<u32>
-where
-    R: AsyncRead + Unpin,
-{
-    let mut val = 0;
-    let mut cur_val = [0];
-    for i in 0..5 {
-        reader.read_exact(&mut cur_val).await?;
-        val += ((cur_val[0] & 0x7f) as u32) << (i * 7);
-        if (cur_val[0] & 0x80) == 0x00 {
-            break;
+    async fn decrypt(&mut self) -> Result<(), ReadError> {
+        #[cfg(feature = "workpool")]
+        if self.decoder.pending_decryption() > self.unblock_threshold as usize {
+            let (mut buf, range, mut decryptor) = self.decoder.take_decryption()?;
+            let workpool = self.workpool.clone().unwrap_or_default();
+            let (buf, decryptor, elapsed) = workpool
+                .run(move || {
+                    let start = std::time::Instant::now();
+                    crate::helpers::decrypt(&mut buf[range], &mut decryptor);
+                    (buf, decryptor, start.elapsed())
+                })
+                .await
+                .map_err(std::io::Error::from)?;
+            self.decoder.finish_decryption(buf, decryptor, elapsed);
+            return Ok(());
         }
+        self.decoder.decrypt_pending()?;
+        Ok(())
     }
-    Ok(val)
 }
@@ -0,0 +1,87 @@
+use futures_lite::io::{BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+use super::{Connection, ReadHalf, WriteHalf};
+
+impl<R, W> Connection<Compat<R>, Compat<W>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    /// Like `Connection::new`, but taking tokio's io traits.
+    #[allow(clippy::type_complexity)]
+    pub fn from_tokio(
+        reader: R,
+        writer: W,
+    ) -> Connection<BufReader<Compat<R>>, BufWriter<Compat<W>>> {
+        Connection::new(reader.compat(), writer.compat_write())
+    }
+
+    /// Like `Connection::unbuffered`, but taking tokio's io traits.
+    pub fn from_tokio_unbuffered(reader: R, writer: W) -> Self {
+        Connection::unbuffered(reader.compat(), writer.compat_write())
+    }
+}
+
+impl Connection<Compat<OwnedReadHalf>, Compat<OwnedWriteHalf>> {
+    /// Splits the stream using `TcpStream::into_split` and constructs a buffered connection.
+    #[allow(clippy::type_complexity)]
+    pub fn from_tcp_stream(
+        stream: TcpStream,
+    ) -> Connection<BufReader<Compat<OwnedReadHalf>>, BufWriter<Compat<OwnedWriteHalf>>> {
+        let (reader, writer) = stream.into_split();
+        Connection::from_tokio(reader, writer)
+    }
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> ReadHalf<Compat<R>> {
+    pub fn from_tokio(reader: R) -> Self {
+        ReadHalf::new(reader.compat())
+    }
+}
+
+impl<W: tokio::io::AsyncWrite + Unpin> WriteHalf<Compat<W>> {
+    pub fn from_tokio(writer: W) -> Self {
+        WriteHalf::new(writer.compat_write())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoder;
+    use miners_encoding::attrs::Rest;
+
+    #[tokio::test]
+    async fn duplex() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (server_read, server_write) = tokio::io::split(server);
+        let mut client = Connection::from_tokio(client_read, client_write);
+        let mut server = Connection::from_tokio(server_read, server_write);
+        client.enable_compression(256);
+        client.enable_encryption(&[1; 16]).unwrap();
+        server.enable_compression(256);
+        server.enable_encryption(&[1; 16]).unwrap();
+
+        // incompressible and large enough to be offloaded
+        let data: Vec<u8> = (0..1u32 << 16)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            let mut encoder = Encoder::new();
+            let encoded = encoder.encode(0x21, Rest::from(&sent[..])).unwrap();
+            server.write_half.write(encoded).await.unwrap();
+            server.write_half.flush().await.unwrap();
+        });
+
+        let packet = client.read_half.read_encoded().await.unwrap();
+        let packet = packet.into_packet().unwrap();
+        assert_eq!(packet.id, 0x21);
+        assert_eq!(packet.data, &data[..]);
+        writer.await.unwrap();
+    }
+}
@@ -1,43 +1,101 @@
+use miners_packet::Direction;
+
+use crate::capture::Capture;
+use crate::codec::FrameEncoder;
+use crate::encoding::EncodedData;
 use crate::encoding::Encoder;
-use crate::{encoding::EncodedData, packing::Compression, writer::Writer};
-use flate2::Compress;
-use futures_lite::AsyncWrite;
+use crate::legacy::{LegacyPing, LegacyStatus};
+use crate::packing::{PackedData, PreparedPacket};
+use crate::proxy_protocol::ProxyHeader;
+use crate::stats::TrafficStats;
+#[cfg(feature = "workpool")]
+use crate::{workpool::WorkPool, DEFAULT_COMPRESSION_UNBLOCK_THRESHOLD, DEFAULT_UNBLOCK_THRESHOLD};
+use aes::cipher::InvalidLength;
+use futures_lite::{AsyncWrite, AsyncWriteExt};
 use std::io;
 
 pub struct WriteHalf<W> {
-    writer: Writer<W>,
-    compression: Option<Compression>,
-    compress_buf: Vec<u8>,
+    encoder: FrameEncoder,
+    /// prepared packets are copied here to be encrypted
+    writebuf: Vec<u8>,
+    pub(super) writer: W,
+    capture: Option<(Capture, Direction)>,
+    #[cfg(feature = "workpool")]
+    unblock_threshold: u32,
+    #[cfg(feature = "workpool")]
+    compression_unblock_threshold: u32,
+    #[cfg(feature = "workpool")]
+    workpool: Option<WorkPool>,
 }
 
-const DEFAULT_COMPRESS_BUF_CAPACITY: usize = 4096;
-
 impl<W> WriteHalf<W> {
     pub fn new(inner: W) -> WriteHalf<W> {
-        WriteHalf {
-            writer: Writer::new(inner),
-            compression: None,
-            compress_buf: Vec::with_capacity(DEFAULT_COMPRESS_BUF_CAPACITY),
-        }
+        Self::with_encoder(inner, FrameEncoder::new())
     }
     pub fn new_with_capacity(inner: W, capacity: u32) -> WriteHalf<W> {
+        Self::with_encoder(inner, FrameEncoder::with_capacity(capacity as usize))
+    }
+    fn with_encoder(inner: W, encoder: FrameEncoder) -> WriteHalf<W> {
         WriteHalf {
-            writer: Writer::new(inner),
-            compression: None,
-            compress_buf: Vec::with_capacity(capacity as usize),
+            encoder,
+            writebuf: Vec::new(),
+            writer: inner,
+            capture: None,
+            #[cfg(feature = "workpool")]
+            unblock_threshold: DEFAULT_UNBLOCK_THRESHOLD,
+            #[cfg(feature = "workpool")]
+            compression_unblock_threshold: DEFAULT_COMPRESSION_UNBLOCK_THRESHOLD,
+            #[cfg(feature = "workpool")]
+            workpool: None,
         }
     }
-    pub fn enable_encryption(&mut self, encryptor: cfb8::Encryptor<aes::Aes128>) {
-        self.writer.enable_encryption(encryptor)
+    pub fn enable_encryption(&mut self, key: &[u8]) -> Result<(), InvalidLength> {
+        self.encoder.enable_encryption(key)
     }
 
+    /// Enables compression for packets with a length of at least `threshold`.
+    ///
+    /// A negative threshold disables compression, as with `SetCompression27`.
     pub fn enable_compression(&mut self, threshold: i32) {
-        self.compression = Some(
-            Compression {
-                threshold: threshold as u32, // TODO: Fix this to not use as casts
-                zlib: Compress::new(flate2::Compression::fast(), true),
-            }
-        )
+        self.encoder.enable_compression(threshold)
+    }
+
+    /// Records every packet written to `capture`, which are sent in `direction`.
+    pub fn set_capture(&mut self, capture: Capture, direction: Direction) {
+        self.capture = Some((capture, direction));
+    }
+
+    /// The capture recording the packets written, if any.
+    pub fn capture(&self) -> Option<&Capture> {
+        self.capture.as_ref().map(|(capture, _)| capture)
+    }
+
+    pub fn stats(&self) -> &TrafficStats {
+        self.encoder.stats()
+    }
+
+    pub fn stats_mut(&mut self) -> &mut TrafficStats {
+        self.encoder.stats_mut()
+    }
+
+    #[cfg(feature = "workpool")]
+    /// sets the threshold which determines if to offload
+    /// packet encryption using cfb8/aes128 to the workpool
+    pub fn set_blocking_threshold(&mut self, threshold: u32) {
+        self.unblock_threshold = threshold;
+    }
+
+    #[cfg(feature = "workpool")]
+    /// sets the threshold which determines if to offload
+    /// packet compression using zlib to the workpool
+    pub fn set_compression_blocking_threshold(&mut self, threshold: u32) {
+        self.compression_unblock_threshold = threshold;
+    }
+
+    #[cfg(feature = "workpool")]
+    /// sets the pool work is offloaded to instead of the default one
+    pub fn set_workpool(&mut self, workpool: WorkPool) {
+        self.workpool = Some(workpool);
     }
 }
 
@@ -45,13 +103,116 @@ impl<W> WriteHalf<W>
 where
     W: AsyncWrite + Unpin,
 {
-    pub async fn write<'encoded>(&mut self, encoded: EncodedData<'encoded>) -> io::Result<()> {
-        let packed = encoded.split_pack(self.compression.as_mut(), &mut self.compress_buf);
-        self.writer.write(packed).await
+    /// Writes the frame for `encoded` using a single `write_all`.
+    pub async fn write(&mut self, encoded: EncodedData<'_>) -> io::Result<()> {
+        if let Some((capture, direction)) = &self.capture {
+            capture.record(*direction, &encoded);
+        }
+        let packed = self.pack(encoded).await?;
+        let (buf, start) = self.encoder.finish(packed);
+        self.encrypt(buf, start).await?;
+        self.writer.write_all(&buf[start..]).await
+    }
+    /// Writes a prepared packet, which only has to be encrypted for this connection.
+    pub async fn write_prepared(&mut self, packet: &PreparedPacket) -> io::Result<()> {
+        self.encoder.start_prepared(packet)?;
+        if let Some((capture, direction)) = &self.capture {
+            if let Ok(mut buf) = crate::codec::unpack_prepared(packet) {
+                capture.record(*direction, &EncodedData::new(&mut buf));
+            }
+        }
+        if !self.encoder.is_encrypted() {
+            return self.writer.write_all(packet.frame()).await;
+        }
+        let mut buf = std::mem::take(&mut self.writebuf);
+        buf.clear();
+        buf.extend_from_slice(packet.frame());
+        self.encrypt(&mut buf, 0).await?;
+        let res = self.writer.write_all(&buf).await;
+        self.writebuf = buf;
+        res
     }
     pub async fn flush(&mut self) -> io::Result<()> {
         self.writer.flush().await
     }
+
+    /// Writes a legacy ping, which has to be the first thing sent.
+    pub async fn write_legacy_ping(&mut self, ping: &LegacyPing) -> io::Result<()> {
+        self.writer.write_all(&ping.encode()).await
+    }
+
+    /// Writes the answer to a legacy ping, after which the connection should be closed.
+    pub async fn write_legacy_status(
+        &mut self,
+        status: &LegacyStatus,
+        ping: &LegacyPing,
+    ) -> io::Result<()> {
+        self.writer.write_all(&status.encode(ping)).await
+    }
+
+    /// Writes a PROXY protocol header, which has to be the first thing sent.
+    pub async fn write_proxy_header(&mut self, header: &ProxyHeader) -> io::Result<()> {
+        let header = header
+            .encode()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.writer.write_all(&header).await
+    }
+
+    #[allow(unused_mut)]
+    async fn pack<'a>(&mut self, mut encoded: EncodedData<'a>) -> io::Result<PackedData<'a>> {
+        #[cfg(feature = "workpool")]
+        if encoded.uncompressed_len() >= self.compression_unblock_threshold {
+            let uncompressed_len = encoded.uncompressed_len();
+            if let Some(mut compressor) = self.encoder.take_compressor(&encoded)? {
+                let mut data = std::mem::take(&mut *encoded.buf);
+                let start = encoded.start;
+                let workpool = self.workpool.clone().unwrap_or_default();
+                let (data, compressor, res, elapsed) = workpool
+                    .run(move || {
+                        let start_time = std::time::Instant::now();
+                        let res = compressor.compress(EncodedData {
+                            buf: &mut data,
+                            start,
+                        });
+                        (data, compressor, res, start_time.elapsed())
+                    })
+                    .await?;
+                *encoded.buf = data;
+                return self.encoder.finish_compression(
+                    compressor,
+                    res,
+                    encoded,
+                    uncompressed_len,
+                    elapsed,
+                );
+            }
+        }
+        self.encoder.pack(encoded)
+    }
+
+    /// Encrypts the frame starting at `start` in place.
+    #[cfg_attr(not(feature = "workpool"), allow(clippy::ptr_arg))]
+    async fn encrypt(&mut self, buf: &mut Vec<u8>, start: usize) -> io::Result<()> {
+        #[cfg(feature = "workpool")]
+        if buf.len() - start >= self.unblock_threshold as usize {
+            if let Some(mut encryptor) = self.encoder.take_encryptor()? {
+                let mut data = std::mem::take(buf);
+                let workpool = self.workpool.clone().unwrap_or_default();
+                let (data, encryptor, elapsed) = workpool
+                    .run(move || {
+                        let start_time = std::time::Instant::now();
+                        crate::helpers::encrypt(&mut data[start..], &mut encryptor);
+                        (data, encryptor, start_time.elapsed())
+                    })
+                    .await?;
+                *buf = data;
+                self.encoder.restore_encryptor(encryptor, elapsed);
+                return Ok(());
+            }
+        }
+        self.encoder.encrypt(&mut buf[start..])?;
+        Ok(())
+    }
 }
 
 impl<W> WriteHalf<W>
@@ -0,0 +1,167 @@
+//! Helpers for the encryption handshake of the login.
+//!
+//! The server sends its RSA public key in an `EncryptionRequest`, the client
+//! answers with a random shared secret and the verify token, both encrypted
+//! with that key. Afterwards both sides use the shared secret as the key
+//! passed to `enable_encryption`.
+
+use rsa::pkcs8::{DecodePublicKey, EncodePublicKey};
+use rsa::{Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use sha1::{Digest, Sha1};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CryptoError {
+    #[error("invalid public key: {0}")]
+    InvalidKey(#[from] rsa::pkcs8::spki::Error),
+    #[error(transparent)]
+    Rsa(#[from] rsa::Error),
+    #[error("the shared secret is {0} bytes long instead of 16")]
+    SharedSecretLength(usize),
+}
+
+/// The public key of a server, as sent in the `EncryptionRequest`.
+#[derive(Debug, Clone)]
+pub struct PublicKey(RsaPublicKey);
+
+impl PublicKey {
+    /// Decodes a DER encoded SubjectPublicKeyInfo.
+    pub fn from_der(der: &[u8]) -> Result<Self, CryptoError> {
+        Ok(PublicKey(RsaPublicKey::from_public_key_der(der)?))
+    }
+
+    /// Encodes the key as DER encoded SubjectPublicKeyInfo.
+    pub fn to_der(&self) -> Result<Vec<u8>, CryptoError> {
+        Ok(self.0.to_public_key_der()?.into_vec())
+    }
+
+    /// Encrypts `data` using PKCS#1 v1.5 padding.
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Ok(self
+            .0
+            .encrypt(&mut rand::thread_rng(), Pkcs1v15Encrypt, data)?)
+    }
+}
+
+/// The key pair of a server, the vanilla server generates one on startup.
+#[derive(Debug, Clone)]
+pub struct KeyPair {
+    private: RsaPrivateKey,
+    der: Vec<u8>,
+}
+
+impl KeyPair {
+    /// Generates a 1024 bit key pair, which takes a moment.
+    pub fn generate() -> Result<Self, CryptoError> {
+        let private = RsaPrivateKey::new(&mut rand::thread_rng(), 1024)?;
+        let der = private.to_public_key().to_public_key_der()?.into_vec();
+        Ok(KeyPair { private, der })
+    }
+
+    /// The DER encoded public key, as sent in the `EncryptionRequest`.
+    pub fn public_key_der(&self) -> &[u8] {
+        &self.der
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.private.to_public_key())
+    }
+
+    /// Decrypts `data` encrypted with the public key using PKCS#1 v1.5 padding.
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        Ok(self.private.decrypt(Pkcs1v15Encrypt, data)?)
+    }
+
+    /// Decrypts the shared secret of the `EncryptionResponse`,
+    /// which is then passed to `enable_encryption`.
+    pub fn decrypt_shared_secret(&self, data: &[u8]) -> Result<[u8; 16], CryptoError> {
+        let secret = self.decrypt(data)?;
+        let len = secret.len();
+        secret
+            .try_into()
+            .map_err(|_| CryptoError::SharedSecretLength(len))
+    }
+}
+
+/// Generates a random verify token for the `EncryptionRequest`.
+pub fn generate_verify_token() -> [u8; 4] {
+    rand::random()
+}
+
+/// Generates a random shared secret to be used as the encryption key.
+pub fn generate_shared_secret() -> [u8; 16] {
+    rand::random()
+}
+
+/// The hash identifying a login to the session server.
+///
+/// It is the SHA-1 digest of the server id, shared secret and public key,
+/// formatted as a signed two's complement number in hex without leading zeros.
+pub fn server_hash(server_id: &str, shared_secret: &[u8], public_key: &[u8]) -> String {
+    let mut digest: [u8; 20] = Sha1::new()
+        .chain_update(server_id)
+        .chain_update(shared_secret)
+        .chain_update(public_key)
+        .finalize()
+        .into();
+    let negative = digest[0] & 0x80 != 0;
+    if negative {
+        // two's complement negation
+        let mut carry = true;
+        for byte in digest.iter_mut().rev() {
+            let (negated, overflow) = (!*byte).overflowing_add(carry as u8);
+            *byte = negated;
+            carry = overflow;
+        }
+    }
+    let hex: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+    let hex = hex.trim_start_matches('0');
+    if negative {
+        format!("-{hex}")
+    } else {
+        hex.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encryption() {
+        let key_pair = KeyPair::generate().unwrap();
+        let public_key = PublicKey::from_der(key_pair.public_key_der()).unwrap();
+        assert_eq!(public_key.to_der().unwrap(), key_pair.public_key_der());
+
+        let shared_secret = generate_shared_secret();
+        let encrypted = public_key.encrypt(&shared_secret).unwrap();
+        // the padding is random
+        assert_ne!(encrypted, public_key.encrypt(&shared_secret).unwrap());
+        assert_eq!(
+            key_pair.decrypt_shared_secret(&encrypted).unwrap(),
+            shared_secret
+        );
+
+        let verify_token = key_pair.public_key().encrypt(&[1, 2, 3, 4]).unwrap();
+        assert_eq!(key_pair.decrypt(&verify_token).unwrap(), [1, 2, 3, 4]);
+        assert!(matches!(
+            key_pair.decrypt_shared_secret(&verify_token),
+            Err(CryptoError::SharedSecretLength(4))
+        ));
+    }
+
+    #[test]
+    fn hash() {
+        assert_eq!(
+            server_hash("Notch", &[], &[]),
+            "4ed1f46bbe04bc756bcb17c0c7ce3e4632f06a48"
+        );
+        assert_eq!(
+            server_hash("jeb_", &[], &[]),
+            "-7c9d5b0044c130109a5d7b5fb5c317c02b4e28c1"
+        );
+        assert_eq!(
+            server_hash("simon", &[], &[]),
+            "88e16a1019277b15d58faf0541e11910eb756f6"
+        );
+    }
+}
@@ -5,88 +5,105 @@ use {
 
 use miners_packet::RawPacket;
 
-use crate::packing::{Compression, Compressor, PackedData};
+use crate::packing::{Compressor, PackedData, PreparedPacket};
+
+/// The space reserved in front of the packet for the frame header,
+/// which consists of up to two varints spanning 1-5 bytes each
+pub(crate) const HEADER_SPACE: usize = 10;
 
 /// Holds a mutable reference to a buffer with the following layout
 ///
-/// | marker | id | encoded data |
+/// | header space | marker | id | encoded data |
+///                ^ start
 ///
-/// where marker is a single `0` byte, id a varint spanning 1-5 bytes
-/// and encoded data the packet data
+/// where header space is reserved for the frame header, marker is
+/// a single `0` byte, id a varint spanning 1-5 bytes and encoded
+/// data the packet data
 ///
 /// It holds a mutable reference because the underlying data is being
 /// mutated under certain circumstances when writing, more specifically
-/// when encrypting. this saves allocations
-pub struct EncodedData<'encoded>(pub(crate) &'encoded mut Vec<u8>);
+/// when prefixing the header and encrypting. this saves allocations
+/// and allows writing a whole frame at once
+pub struct EncodedData<'encoded> {
+    pub(crate) buf: &'encoded mut Vec<u8>,
+    pub(crate) start: usize,
+}
 impl<'encoded> EncodedData<'encoded> {
+    /// Wraps an encoding buffer with the marker at `HEADER_SPACE`.
+    pub(crate) fn new(buf: &'encoded mut Vec<u8>) -> Self {
+        EncodedData {
+            buf,
+            start: HEADER_SPACE,
+        }
+    }
     pub(crate) fn zero_prefixed(self) -> PackedData<'encoded> {
-        PackedData(self.0, false)
+        PackedData::new(self.buf, self.start, None)
     }
     pub(crate) fn uncompressed_len(&self) -> u32 {
-        self.0.len() as u32 - 1
+        (self.buf.len() - self.start) as u32 - 1
+    }
+    /// The id and data, without the marker
+    pub(crate) fn uncompressed(&self) -> &[u8] {
+        &self.buf[self.start + 1..]
+    }
+    /// The packet id, `-1` if it is malformed
+    pub(crate) fn id(&self) -> i32 {
+        match crate::codec::parse_varint(self.uncompressed()) {
+            Ok(Some((id, _))) => id as i32,
+            _ => -1,
+        }
     }
     fn stripped_marker(self) -> PackedData<'encoded> {
-        PackedData(self.0, true)
+        PackedData::new(self.buf, self.start + 1, None)
     }
     /// Copies the data of an EncodedData reference to a new location,
     /// returning a second one, such "forking" the data
     pub fn fork<'fork>(&self, fork_location: &'fork mut Vec<u8>) -> EncodedData<'fork> {
         fork_location.clear();
-        fork_location.extend_from_slice(self.0);
-        EncodedData(fork_location)
+        fork_location.extend_from_slice(self.buf);
+        EncodedData {
+            buf: fork_location,
+            start: self.start,
+        }
     }
     /// Constructs a new owned accessor to a vector holding packet data
+    /// without any header space, such starting with the marker
     ///
     /// # Safety
     ///
     /// the caller must ensure that the referenced vector contains valid
     /// data, else a panic might occur, for example when the vector is empty
-    pub unsafe fn from_raw(raw: &mut Vec<u8>) -> EncodedData {
-        EncodedData(raw)
+    pub unsafe fn from_raw(raw: &mut Vec<u8>) -> EncodedData<'_> {
+        EncodedData { buf: raw, start: 0 }
     }
-    pub fn to_packet(&self) -> decode::Result<RawPacket> {
-        let mut cursor = std::io::Cursor::new(&self.0[1..]);
+    pub fn to_packet(&self) -> decode::Result<RawPacket<'_>> {
+        let data = self.uncompressed();
+        let mut cursor = std::io::Cursor::new(data);
 
         let id = miners_encoding::attrs::Var::decode(&mut cursor)?.into_inner();
         let pos = cursor.position() as usize;
 
-        Ok(RawPacket::new(id, &self.0[pos + 1..]))
+        Ok(RawPacket::new(id, &data[pos..]))
     }
     pub fn into_packet(self) -> decode::Result<RawPacket<'encoded>> {
-        let mut cursor = std::io::Cursor::new(&self.0[1..]);
+        let buf: &'encoded Vec<u8> = self.buf;
+        let data = &buf[self.start + 1..];
+        let mut cursor = std::io::Cursor::new(data);
 
         let id = miners_encoding::attrs::Var::decode(&mut cursor)?.into_inner();
         let pos = cursor.position() as usize;
 
-        Ok(RawPacket::new(id, &self.0[pos + 1..]))
+        Ok(RawPacket::new(id, &data[pos..]))
     }
 }
 impl<'encoded> EncodedData<'encoded> {
-    pub(crate) fn split_pack<'compressed, 'ret>(
+    pub(crate) fn pack(
         self,
-        compressor: Option<&mut Compression>,
-        buf: &'compressed mut Vec<u8>,
-    ) -> PackedData<'ret>
-    where
-        'compressed: 'ret,
-        'encoded: 'ret,
-    {
-        match compressor {
-            Some(compression) => compression.maybe_compress(self, buf),
-            None => self.stripped_marker(),
-        }
-    }
-    pub(crate) fn pack<'compression, 'ret>(
-        self,
-        compression: Option<&'compression mut Compressor>,
-    ) -> PackedData<'ret>
-    where
-        'compression: 'ret,
-        'encoded: 'ret,
-    {
+        compression: Option<&mut Compressor>,
+    ) -> std::io::Result<PackedData<'encoded>> {
         match compression {
             Some(compression) => compression.maybe_compress(self),
-            None => self.stripped_marker(),
+            None => Ok(self.stripped_marker()),
         }
     }
 }
@@ -106,27 +123,51 @@ impl Encoder {
     }
 }
 impl Encoder {
-    pub fn encode(&mut self, id: i32, data: impl Encode) -> encode::Result<EncodedData> {
+    pub fn encode(&mut self, id: i32, data: impl Encode) -> encode::Result<EncodedData<'_>> {
         self.encodebuf.clear();
+        self.encodebuf.resize(HEADER_SPACE, 0);
         self.encodebuf.push(0);
         varint_vec(id as u32, &mut self.encodebuf);
         data.encode(&mut self.encodebuf)?;
-        Ok(EncodedData(&mut self.encodebuf))
+        Ok(EncodedData {
+            buf: &mut self.encodebuf,
+            start: HEADER_SPACE,
+        })
     }
     pub fn encode_packet<P>(
         &mut self,
         version: miners_version::ProtocolVersion,
         packet: P,
-    ) -> Option<encode::Result<EncodedData>>
+    ) -> Option<encode::Result<EncodedData<'_>>>
     where
         P: miners_packet::Packet,
     {
         self.encodebuf.clear();
+        self.encodebuf.resize(HEADER_SPACE, 0);
         self.encodebuf.push(0);
         match packet.encode_for_version(version, &mut self.encodebuf) {
-            Some(Ok(())) => Some(Ok(EncodedData(&mut self.encodebuf))),
+            Some(Ok(())) => Some(Ok(EncodedData {
+                buf: &mut self.encodebuf,
+                start: HEADER_SPACE,
+            })),
             Some(Err(e)) => Some(Err(e)),
             None => None,
         }
     }
+    /// Encodes a packet and prepares it for connections with the given
+    /// compression threshold, see `PreparedPacket::new`.
+    pub fn prepare_packet<P>(
+        &mut self,
+        version: miners_version::ProtocolVersion,
+        packet: P,
+        compression: i32,
+    ) -> Option<encode::Result<PreparedPacket>>
+    where
+        P: miners_packet::Packet,
+    {
+        match self.encode_packet(version, packet)? {
+            Ok(encoded) => Some(PreparedPacket::new(encoded, compression).map_err(Into::into)),
+            Err(e) => Some(Err(e)),
+        }
+    }
 }
@@ -0,0 +1,410 @@
+//! The server list ping of clients from before the netty rewrite in 1.7.
+//!
+//! Legacy clients open the connection with `0xFE` instead of a frame length,
+//! and the server answers with a `0xFF` kick packet holding the status
+//! as a UTF-16 string, closing the connection afterwards. There are three
+//! flavours of the ping:
+//!
+//! - beta 1.8 to 1.3 send `0xFE` and get `motd§online§max` back,
+//! - 1.4 and 1.5 send `0xFE 0x01` and get `§1\0protocol\0version\0motd\0online\0max`,
+//! - 1.6 additionally sends an `MC|PingHost` plugin message with the
+//!   address it connected to and gets the same answer as 1.4.
+
+/// The first byte of a legacy ping.
+pub const LEGACY_PING: u8 = 0xFE;
+/// The id of the kick packet answering a legacy ping.
+pub const LEGACY_KICK: u8 = 0xFF;
+/// The protocol version of 1.6.4, the last one before netty.
+pub const LEGACY_PROTOCOL: u8 = 78;
+
+const PING_HOST_CHANNEL: &str = "MC|PingHost";
+
+#[derive(Debug, thiserror::Error)]
+pub enum LegacyError {
+    #[error("the legacy ping is malformed")]
+    InvalidPing,
+    #[error("the legacy status is malformed: {0:?}")]
+    InvalidStatus(String),
+    #[error("the legacy packet contains invalid UTF-16")]
+    Utf16,
+}
+
+/// A legacy server list ping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LegacyPing {
+    /// Sent by beta 1.8 to 1.3.
+    Beta,
+    /// Sent by 1.4 and 1.5.
+    V1_4,
+    /// Sent by 1.6.
+    V1_6 {
+        protocol: u8,
+        host: String,
+        port: i32,
+    },
+}
+
+impl LegacyPing {
+    /// Whether `buf` starts with a legacy ping instead of a netty frame,
+    /// `None` if more bytes are needed to tell.
+    ///
+    /// Frame lengths like 254 start with `0xFE` as well, so like vanilla
+    /// servers, anything not continuing as a ping is left to netty.
+    pub fn detect(buf: &[u8]) -> Option<bool> {
+        match buf {
+            [] => None,
+            [LEGACY_PING] | [LEGACY_PING, 0x01] => Some(true),
+            [LEGACY_PING, 0x01, 0xFA, rest @ ..] => match Reader(rest).string() {
+                Ok(Some(channel)) => Some(channel == PING_HOST_CHANNEL),
+                Ok(None) => None,
+                Err(_) => Some(false),
+            },
+            _ => Some(false),
+        }
+    }
+
+    /// Decodes a ping from the start of `buf`, returning it along with the
+    /// amount of bytes it took, or `None` if more bytes are needed.
+    ///
+    /// As the older pings are prefixes of the newer ones, they are told apart
+    /// by the amount of bytes received, like vanilla servers do. This works
+    /// because clients send the whole ping at once.
+    pub fn decode(buf: &[u8]) -> Result<Option<(LegacyPing, usize)>, LegacyError> {
+        match buf {
+            [] => Ok(None),
+            [LEGACY_PING] => Ok(Some((LegacyPing::Beta, 1))),
+            [LEGACY_PING, 0x01] => Ok(Some((LegacyPing::V1_4, 2))),
+            [LEGACY_PING, 0x01, 0xFA, rest @ ..] => {
+                let mut reader = Reader(rest);
+                let Some(channel) = reader.string()? else {
+                    return Ok(None);
+                };
+                if channel != PING_HOST_CHANNEL {
+                    return Err(LegacyError::InvalidPing);
+                }
+                let Some(len) = reader.u16() else {
+                    return Ok(None);
+                };
+                let Some(data) = reader.take(len as usize) else {
+                    return Ok(None);
+                };
+                let mut data = Reader(data);
+                let ping = match (data.u8(), data.string()?, data.i32()) {
+                    (Some(protocol), Some(host), Some(port)) if data.0.is_empty() => {
+                        LegacyPing::V1_6 {
+                            protocol,
+                            host,
+                            port,
+                        }
+                    }
+                    _ => return Err(LegacyError::InvalidPing),
+                };
+                Ok(Some((ping, buf.len() - reader.0.len())))
+            }
+            _ => Err(LegacyError::InvalidPing),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            LegacyPing::Beta => vec![LEGACY_PING],
+            LegacyPing::V1_4 => vec![LEGACY_PING, 0x01],
+            LegacyPing::V1_6 {
+                protocol,
+                host,
+                port,
+            } => {
+                let mut data = vec![*protocol];
+                write_string(&mut data, host);
+                data.extend_from_slice(&port.to_be_bytes());
+
+                let mut out = vec![LEGACY_PING, 0x01, 0xFA];
+                write_string(&mut out, PING_HOST_CHANNEL);
+                out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                out.extend_from_slice(&data);
+                out
+            }
+        }
+    }
+}
+
+/// The status sent in answer to a legacy ping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegacyStatus {
+    /// Clients show `version` instead of the player count if this
+    /// doesn't match their own protocol version.
+    ///
+    /// Answers to beta pings don't include the version,
+    /// so it is `-1` and `version` is empty for them.
+    pub protocol: i32,
+    pub version: String,
+    pub motd: String,
+    pub online: i32,
+    pub max: i32,
+}
+
+impl LegacyStatus {
+    /// Encodes the kick packet answering `ping`.
+    pub fn encode(&self, ping: &LegacyPing) -> Vec<u8> {
+        let status = match ping {
+            // the fields are separated by `§` which can't be escaped
+            LegacyPing::Beta => format!(
+                "{}§{}§{}",
+                self.motd.replace('§', ""),
+                self.online,
+                self.max
+            ),
+            LegacyPing::V1_4 | LegacyPing::V1_6 { .. } => format!(
+                "§1\0{}\0{}\0{}\0{}\0{}",
+                self.protocol, self.version, self.motd, self.online, self.max
+            ),
+        };
+        let mut out = vec![LEGACY_KICK];
+        write_string(&mut out, &status);
+        out
+    }
+
+    /// Decodes a kick packet from the start of `buf`, returning the status
+    /// along with the amount of bytes it took, or `None` if more bytes are needed.
+    pub fn decode(buf: &[u8]) -> Result<Option<(LegacyStatus, usize)>, LegacyError> {
+        let Some((&id, rest)) = buf.split_first() else {
+            return Ok(None);
+        };
+        if id != LEGACY_KICK {
+            return Err(LegacyError::InvalidStatus(format!("packet id {id:#04x}")));
+        }
+        let mut reader = Reader(rest);
+        let Some(status) = reader.string()? else {
+            return Ok(None);
+        };
+        let len = buf.len() - reader.0.len();
+        let invalid = || LegacyError::InvalidStatus(status.clone());
+        let status = if let Some(fields) = status.strip_prefix("§1\0") {
+            let fields: Vec<&str> = fields.split('\0').collect();
+            let [protocol, version, motd, online, max] = fields[..] else {
+                return Err(invalid());
+            };
+            LegacyStatus {
+                protocol: protocol.parse().map_err(|_| invalid())?,
+                version: version.into(),
+                motd: motd.into(),
+                online: online.parse().map_err(|_| invalid())?,
+                max: max.parse().map_err(|_| invalid())?,
+            }
+        } else {
+            let mut fields = status.rsplitn(3, '§');
+            let (Some(max), Some(online), Some(motd)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid());
+            };
+            LegacyStatus {
+                protocol: -1,
+                version: String::new(),
+                motd: motd.into(),
+                online: online.parse().map_err(|_| invalid())?,
+                max: max.parse().map_err(|_| invalid())?,
+            }
+        };
+        Ok(Some((status, len)))
+    }
+}
+
+/// Writes a string prefixed with its length in UTF-16 code units,
+/// cutting it short if it is longer than the prefix can hold.
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    let start = out.len();
+    out.extend_from_slice(&[0, 0]);
+    let mut len = 0u16;
+    let mut units = [0; 2];
+    for c in s.chars() {
+        let units = c.encode_utf16(&mut units);
+        let Some(new_len) = len.checked_add(units.len() as u16) else {
+            break;
+        };
+        for unit in units {
+            out.extend_from_slice(&unit.to_be_bytes());
+        }
+        len = new_len;
+    }
+    out[start..start + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+/// Reads from the front of a slice, returning `None` if it's too short.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        self.take(4)
+            .map(|b| i32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Result<Option<String>, LegacyError> {
+        let Some(len) = self.u16() else {
+            return Ok(None);
+        };
+        let Some(data) = self.take(len as usize * 2) else {
+            return Ok(None);
+        };
+        let units = data
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]));
+        char::decode_utf16(units)
+            .collect::<Result<_, _>>()
+            .map(Some)
+            .map_err(|_| LegacyError::Utf16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::duplex;
+    use futures_lite::future::block_on;
+    use miners_protocol::netty::handshaking::serverbound::{Handshake0, NextState0};
+
+    #[test]
+    fn ping_roundtrip() {
+        let pings = [
+            LegacyPing::Beta,
+            LegacyPing::V1_4,
+            LegacyPing::V1_6 {
+                protocol: 78,
+                host: "localhost".into(),
+                port: 25565,
+            },
+        ];
+        for ping in pings {
+            let encoded = ping.encode();
+            assert_eq!(
+                LegacyPing::decode(&encoded).unwrap(),
+                Some((ping.clone(), encoded.len()))
+            );
+        }
+    }
+
+    #[test]
+    fn incomplete_ping() {
+        let encoded = LegacyPing::V1_6 {
+            protocol: 78,
+            host: "localhost".into(),
+            port: 25565,
+        }
+        .encode();
+        for len in 3..encoded.len() {
+            assert_eq!(LegacyPing::decode(&encoded[..len]).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn netty_frame_starting_like_a_ping() {
+        let (mut client, mut server) = duplex(1024);
+        let handshake = Handshake0 {
+            protocol_version: 47,
+            server_address: "a".repeat(247).into(),
+            server_port: 25565,
+            next_state: NextState0::Login,
+        };
+        block_on(async {
+            let mut encoder = crate::encoding::Encoder::new();
+            let encoded = encoder.encode(0, handshake).unwrap();
+            // the frame length of 254 is encoded as `0xFE 0x01`
+            assert_eq!(encoded.uncompressed_len(), 254);
+            client.write_half.write(encoded).await.unwrap();
+            assert_eq!(server.read_half.read_legacy_ping().await.unwrap(), None);
+            let packet = server.read_half.read_encoded().await.unwrap();
+            let packet = packet.into_packet().unwrap();
+            assert_eq!((packet.id, packet.data.len()), (0, 253));
+        });
+    }
+
+    #[test]
+    fn status_roundtrip() {
+        let status = LegacyStatus {
+            protocol: 127,
+            version: "1.19.2".into(),
+            motd: "A Minecraft Server".into(),
+            online: 3,
+            max: 20,
+        };
+        let encoded = status.encode(&LegacyPing::V1_4);
+        assert_eq!(
+            LegacyStatus::decode(&encoded).unwrap(),
+            Some((status.clone(), encoded.len()))
+        );
+        let beta = LegacyStatus::decode(&status.encode(&LegacyPing::Beta))
+            .unwrap()
+            .unwrap()
+            .0;
+        assert_eq!(
+            (beta.protocol, beta.motd.as_str()),
+            (-1, "A Minecraft Server")
+        );
+    }
+
+    #[test]
+    fn long_status() {
+        let status = LegacyStatus {
+            protocol: 127,
+            version: "miners".into(),
+            motd: "§".repeat(70000),
+            online: 0,
+            max: 1,
+        };
+        let encoded = status.encode(&LegacyPing::V1_4);
+        assert_eq!(encoded.len(), 3 + 2 * u16::MAX as usize);
+    }
+
+    #[test]
+    fn detection() {
+        let (mut client, mut server) = duplex(1024);
+        let ping = LegacyPing::V1_6 {
+            protocol: LEGACY_PROTOCOL,
+            host: "localhost".into(),
+            port: 25565,
+        };
+        let status = LegacyStatus {
+            protocol: 127,
+            version: "miners".into(),
+            motd: "hi".into(),
+            online: 0,
+            max: 1,
+        };
+        block_on(async {
+            client.write_half.write_legacy_ping(&ping).await.unwrap();
+            let received = server.read_half.read_legacy_ping().await.unwrap();
+            assert_eq!(received.as_ref(), Some(&ping));
+            server
+                .write_half
+                .write_legacy_status(&status, &ping)
+                .await
+                .unwrap();
+            assert_eq!(client.read_half.read_legacy_status().await.unwrap(), status);
+
+            // netty clients are left alone
+            let mut encoder = crate::encoding::Encoder::new();
+            let encoded = encoder.encode(0, miners_encoding::attrs::Rest::from(&b"hi"[..]));
+            client.write_half.write(encoded.unwrap()).await.unwrap();
+            assert_eq!(server.read_half.read_legacy_ping().await.unwrap(), None);
+            let packet = server.read_half.read_encoded().await.unwrap();
+            assert_eq!(packet.into_packet().unwrap().id, 0);
+        });
+    }
+}
@@ -1,14 +1,26 @@
 #![deny(clippy::undocumented_unsafe_blocks)]
+pub mod blocking;
+pub mod capture;
+pub(crate) mod cipher;
+pub mod codec;
 pub mod conn;
+#[cfg(feature = "crypto")]
+pub mod crypto;
 pub mod encoding;
+pub mod legacy;
+#[cfg(any(test, feature = "testing"))]
+pub mod memory;
 pub mod packing;
+pub mod proxy_protocol;
+pub mod stats;
 
 #[cfg(feature = "workpool")]
-pub(crate) mod workpool;
-pub mod writer;
+pub mod workpool;
 
 #[cfg(feature = "workpool")]
 const DEFAULT_UNBLOCK_THRESHOLD: u32 = 4096;
+#[cfg(feature = "workpool")]
+const DEFAULT_COMPRESSION_UNBLOCK_THRESHOLD: u32 = 65536;
 
 pub(crate) mod helpers {
 
@@ -18,6 +30,10 @@ pub(crate) mod helpers {
         aes::cipher::BlockEncryptMut::encrypt_blocks_inout_mut(encryptor, chunks);
     }
 
+    pub(crate) fn decrypt(data: &mut [u8], decryptor: &mut crate::cipher::Cfb8Decryptor) {
+        decryptor.decrypt(data);
+    }
+
     pub(crate) fn varint_slice(mut num: u32, buf: &mut [u8; 5]) -> &mut [u8] {
         for i in 0..5 {
             let next_val = num >> 7;
@@ -78,7 +94,7 @@ pub(crate) mod helpers {
     impl std::error::Error for AsyncCancelled {}
     impl From<AsyncCancelled> for std::io::Error {
         fn from(_: AsyncCancelled) -> Self {
-            std::io::Error::new(std::io::ErrorKind::Other, AsyncCancelled)
+            std::io::Error::other(AsyncCancelled)
         }
     }
 }
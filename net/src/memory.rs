@@ -0,0 +1,467 @@
+//! In-memory transports for testing connections without sockets.
+//!
+//! `duplex` returns two connected `Connection`s backed by in-process pipes.
+//! Wrapping a transport in `Chaos` fragments, delays or cuts off its reads
+//! and writes, exercising the paths real networks take. A `SharedBuffer`
+//! keeps what is written to it readable after being moved into a capture.
+
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures_lite::{AsyncRead, AsyncWrite};
+
+use crate::conn::Connection;
+
+struct Pipe {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    reader_closed: bool,
+    writer_closed: bool,
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+fn lock(pipe: &Mutex<Pipe>) -> MutexGuard<'_, Pipe> {
+    pipe.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The reading end of a pipe, returns EOF once the writer is closed or dropped.
+pub struct PipeReader(Arc<Mutex<Pipe>>);
+
+/// The writing end of a pipe, fails with `BrokenPipe` once the reader is dropped.
+pub struct PipeWriter(Arc<Mutex<Pipe>>);
+
+/// Creates a one-way pipe buffering up to `capacity` bytes.
+pub fn pipe(capacity: usize) -> (PipeReader, PipeWriter) {
+    assert!(capacity > 0, "a pipe needs a capacity");
+    let pipe = Arc::new(Mutex::new(Pipe {
+        buf: VecDeque::with_capacity(capacity),
+        capacity,
+        reader_closed: false,
+        writer_closed: false,
+        reader: None,
+        writer: None,
+    }));
+    (PipeReader(pipe.clone()), PipeWriter(pipe))
+}
+
+/// Creates two unbuffered connections, what is written to one is read from the other.
+pub fn duplex(
+    capacity: usize,
+) -> (
+    Connection<PipeReader, PipeWriter>,
+    Connection<PipeReader, PipeWriter>,
+) {
+    let (a_reader, b_writer) = pipe(capacity);
+    let (b_reader, a_writer) = pipe(capacity);
+    (
+        Connection::unbuffered(a_reader, a_writer),
+        Connection::unbuffered(b_reader, b_writer),
+    )
+}
+
+impl AsyncRead for PipeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = lock(&self.0);
+        if pipe.buf.is_empty() {
+            if pipe.writer_closed || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            pipe.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = buf.len().min(pipe.buf.len());
+        for (byte, read) in buf.iter_mut().zip(pipe.buf.drain(..len)) {
+            *byte = read;
+        }
+        if let Some(waker) = pipe.writer.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut pipe = lock(&self.0);
+        pipe.reader_closed = true;
+        if let Some(waker) = pipe.writer.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl PipeWriter {
+    fn close(&self) {
+        let mut pipe = lock(&self.0);
+        pipe.writer_closed = true;
+        if let Some(waker) = pipe.reader.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut pipe = lock(&self.0);
+        if pipe.reader_closed || pipe.writer_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = buf.len().min(pipe.capacity - pipe.buf.len());
+        if len == 0 && !buf.is_empty() {
+            pipe.writer = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        pipe.buf.extend(&buf[..len]);
+        if let Some(waker) = pipe.reader.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A buffer which can be read after being moved into a writer, like a
+/// `Capture`, clones share the same bytes.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    /// A copy of everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        shared.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Wakes the tasks waiting for the latency of `Chaos` transports, all
+/// of them share a single thread which sleeps until the next deadline.
+struct Timer {
+    pending: Mutex<Vec<(Instant, Waker)>>,
+    changed: Condvar,
+}
+
+impl Timer {
+    fn get() -> &'static Timer {
+        static TIMER: OnceLock<Timer> = OnceLock::new();
+        TIMER.get_or_init(|| {
+            std::thread::spawn(|| Timer::get().run());
+            Timer {
+                pending: Mutex::new(Vec::new()),
+                changed: Condvar::new(),
+            }
+        })
+    }
+
+    fn wake_at(&self, deadline: Instant, waker: &Waker) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        // tasks polled again before their deadline are only woken once
+        match pending.iter_mut().find(|(_, w)| w.will_wake(waker)) {
+            Some(entry) => entry.0 = deadline,
+            None => pending.push((deadline, waker.clone())),
+        }
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut expired = Vec::new();
+        loop {
+            let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            pending.retain(|(deadline, waker)| {
+                let is_expired = *deadline <= now;
+                if is_expired {
+                    expired.push(waker.clone());
+                }
+                !is_expired
+            });
+            if expired.is_empty() {
+                let next = pending.iter().map(|(deadline, _)| *deadline).min();
+                // the guard is returned, but the loop locks again anyway
+                match next {
+                    Some(next) => drop(self.changed.wait_timeout(pending, next - now)),
+                    None => drop(self.changed.wait(pending)),
+                }
+                continue;
+            }
+            // wake outside of the lock, in case a task is polled right away
+            drop(pending);
+            expired.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+/// What a `Chaos` transport does to the reads and writes passing through it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChaosConfig {
+    /// The maximum amount of bytes per read or write, `Some(1)` splits
+    /// everything into single bytes.
+    pub fragment: Option<usize>,
+    /// The delay before every read or write completes.
+    pub latency: Option<Duration>,
+    /// The amount of bytes after which the transport disconnects,
+    /// reads return EOF and writes fail with `ConnectionReset`.
+    pub disconnect_after: Option<u64>,
+}
+
+/// Wraps a reader or writer and mangles its io as configured.
+pub struct Chaos<T> {
+    inner: T,
+    config: ChaosConfig,
+    transferred: u64,
+    /// when the pending operation may complete
+    deadline: Option<Instant>,
+}
+
+impl<T> Chaos<T> {
+    pub fn new(inner: T, config: ChaosConfig) -> Self {
+        Self {
+            inner,
+            config,
+            transferred: 0,
+            deadline: None,
+        }
+    }
+
+    /// The amount of bytes read or written so far.
+    pub fn transferred(&self) -> u64 {
+        self.transferred
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Limits `len` by the fragmentation and the remaining bytes
+    /// before disconnecting, `None` if disconnected.
+    fn limit(&self, len: usize) -> Option<usize> {
+        let mut len = len.min(self.config.fragment.unwrap_or(usize::MAX).max(1));
+        if let Some(after) = self.config.disconnect_after {
+            let remaining = after.saturating_sub(self.transferred);
+            if remaining == 0 {
+                return None;
+            }
+            len = len.min(remaining.try_into().unwrap_or(usize::MAX));
+        }
+        Some(len)
+    }
+
+    fn poll_latency(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let Some(latency) = self.config.latency else {
+            return Poll::Ready(());
+        };
+        let deadline = *self
+            .deadline
+            .get_or_insert_with(|| Instant::now() + latency);
+        let now = Instant::now();
+        if now >= deadline {
+            return Poll::Ready(());
+        }
+        Timer::get().wake_at(deadline, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Chaos<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(len) = this.limit(buf.len()) else {
+            return Poll::Ready(Ok(0));
+        };
+        futures_lite::ready!(this.poll_latency(cx));
+        let res = futures_lite::ready!(Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]));
+        this.deadline = None;
+        if let Ok(read) = res {
+            this.transferred += read as u64;
+        }
+        Poll::Ready(res)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Chaos<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(len) = this.limit(buf.len()) else {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        };
+        futures_lite::ready!(this.poll_latency(cx));
+        let res = futures_lite::ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]));
+        this.deadline = None;
+        if let Ok(written) = res {
+            this.transferred += written as u64;
+        }
+        Poll::Ready(res)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::ReadError;
+    use crate::conn::WriteHalf;
+    use crate::encoding::Encoder;
+    use futures_lite::future::block_on;
+    use miners_encoding::attrs::Rest;
+
+    const KEY: &[u8; 16] = b"0123456789abcdef";
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn fragmented_roundtrip() {
+        let fragmented = ChaosConfig {
+            fragment: Some(1),
+            latency: Some(Duration::from_micros(10)),
+            ..Default::default()
+        };
+        let (reader, writer) = pipe(7);
+        let mut write_half = WriteHalf::new(Chaos::new(writer, fragmented));
+        let mut read_half = Connection::unbuffered(Chaos::new(reader, fragmented), Vec::new())
+            .split()
+            .0;
+        write_half.enable_compression(64);
+        write_half.enable_encryption(KEY).unwrap();
+        read_half.enable_compression(64);
+        read_half.enable_encryption(KEY).unwrap();
+
+        let sizes = [0, 10, 63, 64, 500, 5000];
+        let writer = std::thread::spawn(move || {
+            let mut encoder = Encoder::new();
+            for (id, len) in sizes.iter().enumerate() {
+                let data = payload(*len);
+                let encoded = encoder.encode(id as i32, Rest::from(&data[..])).unwrap();
+                block_on(write_half.write(encoded)).unwrap();
+            }
+            block_on(write_half.flush()).unwrap();
+        });
+        for (id, len) in sizes.iter().enumerate() {
+            let packet = block_on(read_half.read_encoded()).unwrap();
+            let packet = packet.into_packet().unwrap();
+            assert_eq!(packet.id, id as i32);
+            assert!(packet.data == &payload(*len)[..]);
+        }
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn latency() {
+        let latency = Duration::from_millis(20);
+        let delayed = ChaosConfig {
+            latency: Some(latency),
+            ..Default::default()
+        };
+        let (mut reader, writer) = pipe(16);
+        let mut writer = Chaos::new(writer, delayed);
+        let start = Instant::now();
+        // the writes wait on the shared timer one after another
+        for _ in 0..3 {
+            block_on(futures_lite::AsyncWriteExt::write_all(&mut writer, &[1])).unwrap();
+        }
+        assert!(start.elapsed() >= latency * 3);
+        let mut buf = [0; 3];
+        block_on(futures_lite::AsyncReadExt::read_exact(
+            &mut reader,
+            &mut buf,
+        ))
+        .unwrap();
+        assert_eq!(buf, [1; 3]);
+    }
+
+    #[test]
+    fn disconnect_mid_frame() {
+        let cut = ChaosConfig {
+            disconnect_after: Some(5),
+            ..Default::default()
+        };
+        let (reader, writer) = pipe(1024);
+        let mut write_half = WriteHalf::new(writer);
+        let mut read_half = Connection::unbuffered(Chaos::new(reader, cut), Vec::new())
+            .split()
+            .0;
+
+        let mut encoder = Encoder::new();
+        let encoded = encoder.encode(1, Rest::from(&payload(100)[..])).unwrap();
+        block_on(write_half.write(encoded)).unwrap();
+        match block_on(read_half.read_encoded()) {
+            Err(ReadError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!("read past the disconnect"),
+        }
+
+        // the writing side notices once the reader is gone
+        drop(read_half);
+        let encoded = encoder.encode(1, Rest::from(&payload(100)[..])).unwrap();
+        let res = block_on(write_half.write(encoded));
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn duplex_connections() {
+        let (mut a, mut b) = duplex(16);
+        let mut encoder = Encoder::new();
+        let encoded = encoder.encode(3, Rest::from(&payload(100)[..])).unwrap();
+        // larger than the pipe, so the write has to wait for the read
+        let write = a.write_half.write(encoded);
+        let read = b.read_half.read_encoded();
+        let (written, read) = block_on(futures_lite::future::zip(write, read));
+        written.unwrap();
+        assert_eq!(read.unwrap().into_packet().unwrap().id, 3);
+    }
+}
@@ -1,4 +1,9 @@
-use crate::{encoding::EncodedData, helpers::varint_vec};
+use std::{io, sync::Arc};
+
+use crate::{
+    encoding::{EncodedData, HEADER_SPACE},
+    helpers::varint_slice,
+};
 
 const ZLIB_BUF_MIN: u32 = 1024;
 
@@ -7,15 +12,13 @@ pub(crate) struct Compression {
     pub(crate) zlib: flate2::Compress,
 }
 impl Compression {
-    fn do_compress<'compressed, 'encoded, 'packed>(
+    /// Compresses `encoded` using `buf`, which afterwards holds
+    /// the uncompressed data to be reused for the next packet.
+    fn do_compress<'encoded>(
         &mut self,
         encoded: EncodedData<'encoded>,
-        buf: &'compressed mut Vec<u8>,
-    ) -> PackedData<'packed>
-    where
-        'encoded: 'packed,
-        'compressed: 'packed,
-    {
+        buf: &mut Vec<u8>,
+    ) -> io::Result<PackedData<'encoded>> {
         buf.clear();
 
         let uncompressed_len = encoded.uncompressed_len();
@@ -23,68 +26,172 @@ impl Compression {
         // either do the max check here or allocate once and make sure
         // to never shrink the buffer, it might fail with small packets
         // as zlib might take up more space than the original data
-        //
-        // note: 5 bytes is the maximum size of the prefixing varint
-        buf.reserve(uncompressed_len.max(ZLIB_BUF_MIN + 5) as usize);
-
-        varint_vec(uncompressed_len, buf);
+        buf.reserve(HEADER_SPACE + uncompressed_len.max(ZLIB_BUF_MIN) as usize);
+        buf.resize(HEADER_SPACE, 0);
 
-        self.zlib
-            .compress_vec(&encoded.0[1..], buf, flate2::FlushCompress::Finish)
-            .ok();
+        let input = encoded.uncompressed();
+        let res = loop {
+            let consumed = self.zlib.total_in() as usize;
+            match self
+                .zlib
+                .compress_vec(&input[consumed..], buf, flate2::FlushCompress::Finish)
+            {
+                Ok(flate2::Status::StreamEnd) => break Ok(()),
+                // incompressible data can end up larger than the input,
+                // `compress_vec` only writes to the spare capacity
+                Ok(_) => buf.reserve(ZLIB_BUF_MIN as usize),
+                Err(e) => break Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+            }
+        };
 
         self.zlib.reset();
+        res?;
 
-        PackedData(buf, false)
+        std::mem::swap(encoded.buf, buf);
+        Ok(PackedData::new(
+            encoded.buf,
+            HEADER_SPACE,
+            Some(uncompressed_len),
+        ))
     }
-    pub(crate) fn maybe_compress<'compressed, 'encoded, 'mutslice>(
+    pub(crate) fn maybe_compress<'encoded>(
         &mut self,
         encoded: EncodedData<'encoded>,
-        buf: &'compressed mut Vec<u8>,
-    ) -> PackedData<'mutslice>
-    where
-        'encoded: 'mutslice,
-        'compressed: 'mutslice,
-    {
+        buf: &mut Vec<u8>,
+    ) -> io::Result<PackedData<'encoded>> {
         if encoded.uncompressed_len() >= self.threshold {
             self.do_compress(encoded, buf)
         } else {
-            encoded.zero_prefixed()
+            Ok(encoded.zero_prefixed())
         }
     }
 }
 
 pub(crate) struct Compressor {
-    compression: Compression,
-    buf: Vec<u8>,
+    pub(crate) compression: Compression,
+    pub(crate) buf: Vec<u8>,
 }
 impl Compressor {
-    pub(crate) fn maybe_compress<'compressed, 'encoded, 'mutslice>(
-        &'compressed mut self,
+    pub(crate) fn new(threshold: u32, capacity: usize) -> Self {
+        Compressor {
+            compression: Compression {
+                threshold,
+                zlib: flate2::Compress::new(flate2::Compression::fast(), true),
+            },
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+    pub(crate) fn maybe_compress<'encoded>(
+        &mut self,
         encoded: EncodedData<'encoded>,
-    ) -> PackedData<'mutslice>
-    where
-        'compressed: 'mutslice,
-        'encoded: 'mutslice,
-    {
+    ) -> io::Result<PackedData<'encoded>> {
         self.compression.maybe_compress(encoded, &mut self.buf)
     }
+    /// Compresses `encoded` regardless of the threshold.
+    #[cfg(feature = "workpool")]
+    pub(crate) fn compress(&mut self, encoded: EncodedData) -> io::Result<()> {
+        self.compression.do_compress(encoded, &mut self.buf)?;
+        Ok(())
+    }
 }
 
-pub struct PackedData<'a>(pub(crate) &'a mut Vec<u8>, pub(crate) bool);
+/// Holds a mutable reference to a buffer with the following layout
+///
+/// | header space | packet |
+///                ^ start
+///
+/// where packet is either the zero-prefixed or compressed packet,
+/// or just the id and data if compression is disabled
+///
+/// The frame header is written into the header space in front
+/// of the packet, so the whole frame ends up in one buffer
+pub struct PackedData<'a> {
+    pub(crate) buf: &'a mut Vec<u8>,
+    pub(crate) start: usize,
+    /// the data length of a compressed packet
+    data_len: Option<u32>,
+}
 impl<'a> PackedData<'a> {
-    pub(crate) fn get(&self) -> &[u8] {
-        &self.0[self.1 as usize..]
-    }
-    pub(crate) fn get_mut(&mut self) -> &mut [u8] {
-        &mut self.0[self.1 as usize..]
+    pub(crate) fn new(buf: &'a mut Vec<u8>, start: usize, data_len: Option<u32>) -> Self {
+        PackedData {
+            buf,
+            start,
+            data_len,
+        }
     }
-    pub(crate) fn len(&self) -> u32 {
-        self.get().len() as u32
+    /// Writes the frame header in front of the packet, returning
+    /// the buffer and the offset at which the frame starts.
+    pub(crate) fn finish(self) -> (&'a mut Vec<u8>, usize) {
+        let mut data_len_buf = [0u8; 5];
+        let data_len: &[u8] = match self.data_len {
+            Some(data_len) => varint_slice(data_len, &mut data_len_buf),
+            None => &[],
+        };
+        let mut frame_len_buf = [0u8; 5];
+        let frame_len = varint_slice(
+            (data_len.len() + self.buf.len() - self.start) as u32,
+            &mut frame_len_buf,
+        );
+
+        let mut start = self.start;
+        let header_len = frame_len.len() + data_len.len();
+        if header_len > start {
+            // not enough space was reserved, as with raw encoded data
+            let missing = header_len - start;
+            self.buf.splice(0..0, std::iter::repeat_n(0, missing));
+            start += missing;
+        }
+        let (frame_len_start, data_len_start) = (start - header_len, start - data_len.len());
+        self.buf[frame_len_start..data_len_start].copy_from_slice(frame_len);
+        self.buf[data_len_start..start].copy_from_slice(data_len);
+        (self.buf, frame_len_start)
     }
     pub fn fork<'fork>(&self, fork_location: &'fork mut Vec<u8>) -> PackedData<'fork> {
         fork_location.clear();
-        fork_location.extend_from_slice(self.0);
-        PackedData(fork_location, self.1)
+        fork_location.extend_from_slice(self.buf);
+        PackedData::new(fork_location, self.start, self.data_len)
+    }
+}
+
+/// A packet which has been encoded and compressed once, ready to be
+/// written to any number of connections using the same compression
+/// threshold, only encrypting it per connection.
+///
+/// Cloning it is cheap, as the frame is reference counted.
+#[derive(Clone)]
+pub struct PreparedPacket {
+    frame: Arc<[u8]>,
+    compression: Option<u32>,
+    id: i32,
+    uncompressed_len: u32,
+}
+impl PreparedPacket {
+    /// Packs `encoded` into a frame for connections with the given
+    /// compression threshold, negative if compression is disabled.
+    pub fn new(encoded: EncodedData, compression: i32) -> io::Result<Self> {
+        let compression = u32::try_from(compression).ok();
+        let (id, uncompressed_len) = (encoded.id(), encoded.uncompressed_len());
+        let mut compressor = compression.map(|threshold| Compressor::new(threshold, 0));
+        let (buf, start) = encoded.pack(compressor.as_mut())?.finish();
+        Ok(PreparedPacket {
+            frame: buf[start..].into(),
+            compression,
+            id,
+            uncompressed_len,
+        })
+    }
+    /// The compression threshold the packet has been prepared for.
+    pub fn compression(&self) -> Option<u32> {
+        self.compression
+    }
+    pub(crate) fn id(&self) -> i32 {
+        self.id
+    }
+    pub(crate) fn uncompressed_len(&self) -> u32 {
+        self.uncompressed_len
+    }
+    /// The unencrypted frame.
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
     }
 }
@@ -0,0 +1,497 @@
+//! The PROXY protocol, used by load balancers to pass on the address of
+//! the client they accepted a connection from.
+//!
+//! The balancer sends a header before anything else, either in the text
+//! format of version 1 or the binary format of version 2, which can carry
+//! additional information in TLVs. As anyone can send a header, it should
+//! only be read on connections which are known to come from a balancer.
+//!
+//! The transport protocol of version 2 headers isn't kept, headers are
+//! always encoded for streams.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest possible version 1 header, including the line break.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+const UNIX_PATH_LEN: usize = 108;
+
+/// The type of the TLV holding the application protocol negotiated by TLS.
+pub const PP2_TYPE_ALPN: u8 = 0x01;
+/// The type of the TLV holding the host name the client connected to.
+pub const PP2_TYPE_AUTHORITY: u8 = 0x02;
+/// The type of the TLV holding a CRC32c checksum of the header.
+pub const PP2_TYPE_CRC32C: u8 = 0x03;
+/// The type of TLVs to be ignored.
+pub const PP2_TYPE_NOOP: u8 = 0x04;
+/// The type of the TLV holding an id of the connection.
+pub const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+/// The type of the TLV holding information about TLS.
+pub const PP2_TYPE_SSL: u8 = 0x20;
+/// The type of the TLV holding the network namespace.
+pub const PP2_TYPE_NETNS: u8 = 0x30;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyProtocolError {
+    #[error("the connection doesn't start with a PROXY header")]
+    Missing,
+    #[error("malformed PROXY header: {0}")]
+    Malformed(&'static str),
+    #[error("unsupported PROXY protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("the PROXY header can't be encoded: {0}")]
+    TooLong(&'static str),
+}
+
+/// The version of the PROXY protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyVersion {
+    /// The text format.
+    V1,
+    /// The binary format.
+    V2,
+}
+
+/// Whether the connection has been proxied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyCommand {
+    /// The connection was opened by the balancer itself, for example for
+    /// health checks, the addresses are to be ignored.
+    Local,
+    /// The connection was opened on behalf of a client.
+    Proxy,
+}
+
+/// The addresses of a proxied connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyAddresses {
+    /// The addresses are unknown, or the connection wasn't proxied.
+    Unspecified,
+    /// The addresses of the client and the address it connected to.
+    Inet {
+        source: SocketAddr,
+        destination: SocketAddr,
+    },
+    /// The paths of unix sockets, without trailing NULs.
+    Unix {
+        source: Vec<u8>,
+        destination: Vec<u8>,
+    },
+}
+
+/// An additional field of a version 2 header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlv {
+    pub kind: u8,
+    pub value: Vec<u8>,
+}
+
+/// A PROXY protocol header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub version: ProxyVersion,
+    pub command: ProxyCommand,
+    pub addresses: ProxyAddresses,
+    /// Always empty for version 1.
+    pub tlvs: Vec<Tlv>,
+}
+
+impl ProxyHeader {
+    /// A header for a connection proxied from `source` to `destination`.
+    pub fn new(version: ProxyVersion, source: SocketAddr, destination: SocketAddr) -> Self {
+        ProxyHeader {
+            version,
+            command: ProxyCommand::Proxy,
+            addresses: ProxyAddresses::Inet {
+                source,
+                destination,
+            },
+            tlvs: Vec::new(),
+        }
+    }
+
+    /// The address of the client, `None` if it is unknown.
+    pub fn source(&self) -> Option<SocketAddr> {
+        match (self.command, &self.addresses) {
+            (ProxyCommand::Proxy, ProxyAddresses::Inet { source, .. }) => Some(*source),
+            _ => None,
+        }
+    }
+
+    /// The value of the first TLV of the type `kind`.
+    pub fn tlv(&self, kind: u8) -> Option<&[u8]> {
+        self.tlvs
+            .iter()
+            .find(|tlv| tlv.kind == kind)
+            .map(|tlv| &tlv.value[..])
+    }
+
+    /// The host name the client connected to, as sent in the `PP2_TYPE_AUTHORITY` TLV.
+    pub fn authority(&self) -> Option<&str> {
+        std::str::from_utf8(self.tlv(PP2_TYPE_AUTHORITY)?).ok()
+    }
+
+    /// Decodes a header of either version from the start of `buf`, returning it
+    /// along with the amount of bytes it took, or `None` if more bytes are needed.
+    pub fn decode(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, ProxyProtocolError> {
+        let starts_with = |prefix: &[u8]| {
+            let len = buf.len().min(prefix.len());
+            buf[..len] == prefix[..len]
+        };
+        if starts_with(V1_PREFIX) && buf.len() >= V1_PREFIX.len() {
+            decode_v1(buf)
+        } else if starts_with(V2_SIGNATURE) && buf.len() >= V2_SIGNATURE.len() {
+            decode_v2(buf)
+        } else if starts_with(V1_PREFIX) || starts_with(V2_SIGNATURE) {
+            Ok(None)
+        } else {
+            Err(ProxyProtocolError::Missing)
+        }
+    }
+
+    /// Encodes the header in its version.
+    ///
+    /// Version 1 can't carry unix socket addresses or TLVs, which are left out.
+    /// Fails if a field of a version 2 header doesn't fit its length.
+    pub fn encode(&self) -> Result<Vec<u8>, ProxyProtocolError> {
+        match self.version {
+            ProxyVersion::V1 => Ok(self.encode_v1()),
+            ProxyVersion::V2 => self.encode_v2(),
+        }
+    }
+
+    fn encode_v1(&self) -> Vec<u8> {
+        let line = match (self.command, &self.addresses) {
+            (
+                ProxyCommand::Proxy,
+                ProxyAddresses::Inet {
+                    source,
+                    destination,
+                },
+            ) if source.is_ipv4() == destination.is_ipv4() => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {family} {} {} {} {}\r\n",
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+            }
+            _ => "PROXY UNKNOWN\r\n".into(),
+        };
+        line.into_bytes()
+    }
+
+    fn encode_v2(&self) -> Result<Vec<u8>, ProxyProtocolError> {
+        let mut out = V2_SIGNATURE.to_vec();
+        out.push(match self.command {
+            ProxyCommand::Local => 0x20,
+            ProxyCommand::Proxy => 0x21,
+        });
+        // the family and the length are filled in once known
+        out.extend_from_slice(&[0, 0, 0]);
+        let family = match &self.addresses {
+            ProxyAddresses::Unspecified => 0x00,
+            ProxyAddresses::Inet {
+                source,
+                destination,
+            } => match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    out.extend_from_slice(&src.octets());
+                    out.extend_from_slice(&dst.octets());
+                    out.extend_from_slice(&source.port().to_be_bytes());
+                    out.extend_from_slice(&destination.port().to_be_bytes());
+                    0x11
+                }
+                (src, dst) => {
+                    out.extend_from_slice(&to_ipv6(src).octets());
+                    out.extend_from_slice(&to_ipv6(dst).octets());
+                    out.extend_from_slice(&source.port().to_be_bytes());
+                    out.extend_from_slice(&destination.port().to_be_bytes());
+                    0x21
+                }
+            },
+            ProxyAddresses::Unix {
+                source,
+                destination,
+            } => {
+                for path in [source, destination] {
+                    if path.len() > UNIX_PATH_LEN {
+                        return Err(ProxyProtocolError::TooLong("a unix path exceeds 108 bytes"));
+                    }
+                    out.extend_from_slice(path);
+                    out.resize(out.len() + UNIX_PATH_LEN - path.len(), 0);
+                }
+                0x31
+            }
+        };
+        for tlv in &self.tlvs {
+            let len = u16::try_from(tlv.value.len())
+                .map_err(|_| ProxyProtocolError::TooLong("a TLV exceeds 65535 bytes"))?;
+            out.push(tlv.kind);
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(&tlv.value);
+        }
+        out[13] = family;
+        let len = u16::try_from(out.len() - V2_HEADER_LEN)
+            .map_err(|_| ProxyProtocolError::TooLong("the header exceeds 65535 bytes"))?;
+        out[14..16].copy_from_slice(&len.to_be_bytes());
+        Ok(out)
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn decode_v1(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, ProxyProtocolError> {
+    let searched = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = searched.windows(2).position(|w| w == b"\r\n") else {
+        return match buf.len() < V1_MAX_LEN {
+            true => Ok(None),
+            false => Err(ProxyProtocolError::Malformed("the line is too long")),
+        };
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| ProxyProtocolError::Malformed("the line isn't ascii"))?;
+    let mut fields = line.split(' ');
+    let family = fields.next().unwrap_or_default();
+    let addresses = match family {
+        "UNKNOWN" => ProxyAddresses::Unspecified,
+        "TCP4" | "TCP6" => {
+            let fields: Vec<&str> = fields.collect();
+            let [source, destination, source_port, destination_port] = fields[..] else {
+                return Err(ProxyProtocolError::Malformed("wrong number of fields"));
+            };
+            let ip = |ip: &str| -> Result<IpAddr, ProxyProtocolError> {
+                let ip = match family {
+                    "TCP4" => ip.parse::<Ipv4Addr>().map(IpAddr::from),
+                    _ => ip.parse::<Ipv6Addr>().map(IpAddr::from),
+                };
+                ip.map_err(|_| ProxyProtocolError::Malformed("invalid address"))
+            };
+            let port = |port: &str| {
+                port.parse::<u16>()
+                    .map_err(|_| ProxyProtocolError::Malformed("invalid port"))
+            };
+            ProxyAddresses::Inet {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            }
+        }
+        _ => return Err(ProxyProtocolError::Malformed("unknown protocol family")),
+    };
+    let header = ProxyHeader {
+        version: ProxyVersion::V1,
+        command: match addresses {
+            ProxyAddresses::Unspecified => ProxyCommand::Local,
+            _ => ProxyCommand::Proxy,
+        },
+        addresses,
+        tlvs: Vec::new(),
+    };
+    Ok(Some((header, end + 2)))
+}
+
+fn decode_v2(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, ProxyProtocolError> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(None);
+    }
+    let version = buf[12] >> 4;
+    if version != 2 {
+        return Err(ProxyProtocolError::UnsupportedVersion(version));
+    }
+    let command = match buf[12] & 0x0F {
+        0x0 => ProxyCommand::Local,
+        0x1 => ProxyCommand::Proxy,
+        _ => return Err(ProxyProtocolError::Malformed("unknown command")),
+    };
+    let family = buf[13] >> 4;
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    let Some(data) = buf.get(V2_HEADER_LEN..V2_HEADER_LEN + len) else {
+        return Ok(None);
+    };
+    let addresses_len = match family {
+        0x0 => 0,
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 2 * UNIX_PATH_LEN,
+        _ => return Err(ProxyProtocolError::Malformed("unknown address family")),
+    };
+    if data.len() < addresses_len {
+        return Err(ProxyProtocolError::Malformed("the addresses are cut short"));
+    }
+    let (addresses, mut rest) = data.split_at(addresses_len);
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    let addresses = match family {
+        0x1 => {
+            let ip = |at: usize| IpAddr::from(<[u8; 4]>::try_from(&addresses[at..at + 4]).unwrap());
+            ProxyAddresses::Inet {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            }
+        }
+        0x2 => {
+            let ip =
+                |at: usize| IpAddr::from(<[u8; 16]>::try_from(&addresses[at..at + 16]).unwrap());
+            ProxyAddresses::Inet {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            }
+        }
+        0x3 => {
+            let path = |path: &[u8]| {
+                let len = path.iter().position(|&b| b == 0).unwrap_or(path.len());
+                path[..len].to_vec()
+            };
+            let (source, destination) = addresses.split_at(UNIX_PATH_LEN);
+            ProxyAddresses::Unix {
+                source: path(source),
+                destination: path(destination),
+            }
+        }
+        _ => ProxyAddresses::Unspecified,
+    };
+
+    let mut tlvs = Vec::new();
+    while !rest.is_empty() {
+        if rest.len() < 3 {
+            return Err(ProxyProtocolError::Malformed("a TLV is cut short"));
+        }
+        let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        let Some(value) = rest.get(3..3 + len) else {
+            return Err(ProxyProtocolError::Malformed("a TLV is cut short"));
+        };
+        tlvs.push(Tlv {
+            kind: rest[0],
+            value: value.to_vec(),
+        });
+        rest = &rest[3 + len..];
+    }
+    let header = ProxyHeader {
+        version: ProxyVersion::V2,
+        command,
+        addresses,
+        tlvs,
+    };
+    Ok(Some((header, V2_HEADER_LEN + len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1() {
+        let line = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 25565\r\n\x10";
+        let (header, len) = ProxyHeader::decode(line).unwrap().unwrap();
+        assert_eq!(len, line.len() - 1);
+        assert_eq!(header.source(), Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(header.encode().unwrap(), &line[..len]);
+
+        for len in 0..len {
+            assert!(ProxyHeader::decode(&line[..len]).unwrap().is_none());
+        }
+        let (unknown, _) = ProxyHeader::decode(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!(unknown.source(), None);
+    }
+
+    #[test]
+    fn v2() {
+        let mut header = ProxyHeader::new(
+            ProxyVersion::V2,
+            "[2001:db8::1]:56324".parse().unwrap(),
+            "[2001:db8::2]:25565".parse().unwrap(),
+        );
+        header.tlvs.push(Tlv {
+            kind: PP2_TYPE_AUTHORITY,
+            value: b"play.example.com".to_vec(),
+        });
+        let encoded = header.encode().unwrap();
+        let (decoded, len) = ProxyHeader::decode(&encoded).unwrap().unwrap();
+        assert_eq!(len, encoded.len());
+        assert_eq!(decoded, header);
+        assert_eq!(decoded.authority(), Some("play.example.com"));
+
+        for len in 0..len {
+            assert!(ProxyHeader::decode(&encoded[..len]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn too_long() {
+        let tlv = |len| Tlv {
+            kind: PP2_TYPE_NOOP,
+            value: vec![0; len],
+        };
+        let mut header = ProxyHeader::new(
+            ProxyVersion::V2,
+            "203.0.113.7:56324".parse().unwrap(),
+            "10.0.0.2:25565".parse().unwrap(),
+        );
+        header.tlvs.push(tlv(u16::MAX as usize + 1));
+        assert!(matches!(
+            header.encode(),
+            Err(ProxyProtocolError::TooLong(_))
+        ));
+
+        // every TLV fits, but not all of them together
+        header.tlvs = vec![tlv(u16::MAX as usize), tlv(0)];
+        assert!(matches!(
+            header.encode(),
+            Err(ProxyProtocolError::TooLong(_))
+        ));
+
+        header.tlvs.clear();
+        header.addresses = ProxyAddresses::Unix {
+            source: vec![b'a'; UNIX_PATH_LEN + 1],
+            destination: b"/run/server.sock".to_vec(),
+        };
+        assert!(matches!(
+            header.encode(),
+            Err(ProxyProtocolError::TooLong(_))
+        ));
+        header.addresses = ProxyAddresses::Unix {
+            source: vec![b'a'; UNIX_PATH_LEN],
+            destination: b"/run/server.sock".to_vec(),
+        };
+        let encoded = header.encode().unwrap();
+        assert_eq!(ProxyHeader::decode(&encoded).unwrap().unwrap().0, header);
+    }
+
+    #[test]
+    fn connection() {
+        let (mut client, mut server) = crate::memory::duplex(1024);
+        let header = ProxyHeader::new(
+            ProxyVersion::V2,
+            "203.0.113.7:56324".parse().unwrap(),
+            "10.0.0.2:25565".parse().unwrap(),
+        );
+        futures_lite::future::block_on(async {
+            client.write_half.write_proxy_header(&header).await.unwrap();
+            let mut encoder = crate::encoding::Encoder::new();
+            let encoded = encoder.encode(0, miners_encoding::attrs::Rest::from(&b"hi"[..]));
+            client.write_half.write(encoded.unwrap()).await.unwrap();
+
+            assert_eq!(server.read_half.read_proxy_header().await.unwrap(), &header);
+            assert_eq!(server.source_addr(), header.source());
+            let packet = server.read_half.read_encoded().await.unwrap();
+            assert_eq!(packet.into_packet().unwrap().id, 0);
+        });
+    }
+
+    #[test]
+    fn missing() {
+        // the length of a handshake, followed by its id
+        assert!(matches!(
+            ProxyHeader::decode(&[0x10, 0x00]),
+            Err(ProxyProtocolError::Missing)
+        ));
+    }
+}
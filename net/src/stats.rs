@@ -0,0 +1,151 @@
+//! Traffic statistics and latency tracking of connections.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Counters of one direction of a connection.
+///
+/// Available from `FrameDecoder::stats` and `FrameEncoder::stats`,
+/// or the `stats` methods of the connection halves wrapping them.
+#[derive(Debug, Clone, Default)]
+pub struct TrafficStats {
+    /// The amount of packets.
+    pub packets: u64,
+    /// The amount of packets per packet id.
+    pub packets_by_id: HashMap<i32, u64>,
+    /// The amount of bytes on the wire, including the frame headers.
+    pub wire_bytes: u64,
+    /// The amount of bytes of the packets before compression, including their ids.
+    pub uncompressed_bytes: u64,
+    /// The time spent compressing or decompressing.
+    pub compression_time: Duration,
+    /// The time spent encrypting or decrypting.
+    pub encryption_time: Duration,
+}
+
+impl TrafficStats {
+    /// The ratio of uncompressed bytes to bytes on the wire,
+    /// `1.0` if nothing has been transferred yet.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.wire_bytes == 0 {
+            return 1.0;
+        }
+        self.uncompressed_bytes as f64 / self.wire_bytes as f64
+    }
+
+    /// Returns the counters and resets them, for reporting per interval.
+    pub fn take(&mut self) -> TrafficStats {
+        std::mem::take(self)
+    }
+
+    pub(crate) fn record_packet(&mut self, id: i32, uncompressed_len: usize) {
+        self.packets += 1;
+        *self.packets_by_id.entry(id).or_default() += 1;
+        self.uncompressed_bytes += uncompressed_len as u64;
+    }
+}
+
+/// The maximum amount of keepalives awaiting a response
+const MAX_PENDING: usize = 16;
+
+/// Estimates the round trip time from keepalive ids and their responses,
+/// using the smoothing of TCP (RFC 6298).
+///
+/// The estimator doesn't know about packets, the side sending keepalives
+/// has to call `sent` and `received` with their ids.
+#[derive(Debug, Clone, Default)]
+pub struct RttEstimator {
+    pending: VecDeque<(i64, Instant)>,
+    latest: Option<Duration>,
+    min: Option<Duration>,
+    smoothed: Option<Duration>,
+    variance: Duration,
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a keepalive with `id` has just been sent.
+    pub fn sent(&mut self, id: i64) {
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((id, Instant::now()));
+    }
+
+    /// Records that the response to the keepalive with `id` has just been
+    /// received, returning the round trip time if it was awaited.
+    pub fn received(&mut self, id: i64) -> Option<Duration> {
+        let index = self
+            .pending
+            .iter()
+            .position(|(pending, _)| *pending == id)?;
+        let (_, sent) = self.pending.remove(index)?;
+        // responses to older keepalives won't arrive anymore
+        self.pending.drain(..index);
+
+        let sample = sent.elapsed();
+        self.latest = Some(sample);
+        self.min = Some(self.min.map_or(sample, |min| min.min(sample)));
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(sample);
+                self.variance = sample / 2;
+            }
+            Some(smoothed) => {
+                let deviation = smoothed.abs_diff(sample);
+                self.variance = (self.variance * 3 + deviation) / 4;
+                self.smoothed = Some((smoothed * 7 + sample) / 8);
+            }
+        }
+        Some(sample)
+    }
+
+    /// The most recent round trip time.
+    pub fn latest(&self) -> Option<Duration> {
+        self.latest
+    }
+
+    /// The lowest round trip time observed.
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    /// The smoothed round trip time.
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// The mean deviation of the round trip time.
+    pub fn variance(&self) -> Duration {
+        self.variance
+    }
+
+    /// The amount of keepalives awaiting a response.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtt() {
+        let mut rtt = RttEstimator::new();
+        rtt.sent(1);
+        rtt.sent(2);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(rtt.received(3).is_none());
+        let sample = rtt.received(2).unwrap();
+        assert!(sample >= Duration::from_millis(10));
+        assert_eq!(rtt.smoothed(), Some(sample));
+        assert_eq!(rtt.variance(), sample / 2);
+        // the first keepalive was skipped by the response to the second
+        assert_eq!(rtt.pending(), 0);
+        assert!(rtt.received(1).is_none());
+    }
+}
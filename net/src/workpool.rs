@@ -1,91 +1,286 @@
-use std::{
-    collections::VecDeque,
-    sync::atomic::{AtomicUsize, Ordering},
-};
-
-use futures_channel::oneshot::Sender;
-use parking_lot::Mutex;
-
-use crate::helpers::encrypt;
-
-type Job = (Vec<u8>, usize, Box<cfb8::Encryptor<aes::Aes128>>);
-
-#[allow(clippy::type_complexity)]
-static ENCRYPTION_WORKQUEUE: once_cell::sync::Lazy<
-    Mutex<VecDeque<(Job, Sender<(Vec<u8>, Box<cfb8::Encryptor<aes::Aes128>>)>)>>,
-> = once_cell::sync::Lazy::new(|| Mutex::new(VecDeque::new()));
-static ENCRYPTION_MAX_THREADCOUNT: once_cell::sync::Lazy<usize> =
-    once_cell::sync::Lazy::new(|| {
-        std::env::var("ENCRYPTION_MAX_THREADCOUNT")
-            .ok()
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or_else(|| {
-                std::thread::available_parallelism()
-                    .map(usize::from)
-                    .unwrap_or(4)
-            })
-    });
-static ENCRYPTION_THREADCOUNT: AtomicUsize = AtomicUsize::new(0);
-static WORKTHREADS_CONDVAR: parking_lot::Condvar = parking_lot::Condvar::new();
-
-pub async fn request_encryption(
-    buf: Vec<u8>,
-    enc: Box<cfb8::Encryptor<aes::Aes128>>,
-) -> futures_channel::oneshot::Receiver<(Vec<u8>, Box<cfb8::Encryptor<aes::Aes128>>)> {
-    let len_from_end = buf.len();
-    // SAFETY: len_from_end is valid for sure as it is exactly the
-    // buffers length
-    unsafe { request_partial_encryption(buf, len_from_end, enc).await }
+//! Offloading of cpu heavy work like encryption off the async executor.
+//!
+//! Every connection uses the default pool unless attached to a specific
+//! `WorkPool` using `set_workpool`.
+
+use std::{collections::VecDeque, io, sync::Arc, thread::JoinHandle, time::Duration};
+
+use futures_channel::oneshot::Receiver;
+use parking_lot::{Condvar, Mutex};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The configuration of a `WorkPool`.
+#[derive(Debug, Clone)]
+pub struct WorkPoolConfig {
+    /// The maximum amount of threads running at once.
+    pub max_threads: usize,
+    /// The stack size of each thread.
+    pub stack_size: usize,
+    /// The duration after which an idle thread exits, `None` keeps threads alive.
+    pub idle_timeout: Option<Duration>,
+    /// The prefix of the thread names.
+    pub name: String,
+}
+
+impl Default for WorkPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_threads: std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(4),
+            stack_size: 256 * 1024,
+            idle_timeout: Some(Duration::from_secs(30)),
+            name: "miners-workpool".into(),
+        }
+    }
+}
+
+/// The error returned when a job could not be completed as the pool was shut down.
+#[derive(Debug, thiserror::Error)]
+#[error("the workpool has been shut down")]
+pub struct ShutDown;
+
+impl From<ShutDown> for io::Error {
+    fn from(e: ShutDown) -> Self {
+        io::Error::other(e)
+    }
+}
+
+/// A handle to a pool of threads, cloning it is cheap.
+#[derive(Clone)]
+pub struct WorkPool(Kind);
+
+#[derive(Clone)]
+enum Kind {
+    Threads(Arc<Shared>),
+    #[cfg(feature = "tokio")]
+    Tokio(tokio::runtime::Handle),
 }
 
-/// # Safety
-///
-/// this is safe as long as len_from_end is not longer than buf.len()
-pub(crate) async unsafe fn request_partial_encryption(
-    buf: Vec<u8>,
-    len_from_end: usize,
-    enc: Box<cfb8::Encryptor<aes::Aes128>>,
-) -> futures_channel::oneshot::Receiver<(Vec<u8>, Box<cfb8::Encryptor<aes::Aes128>>)> {
-    let (send, recv) = futures_channel::oneshot::channel();
-
-    let mut lock = ENCRYPTION_WORKQUEUE.lock();
-    lock.push_back(((buf, len_from_end, enc), send));
-    let len = lock.len();
-    drop(lock);
-
-    if len <= *ENCRYPTION_MAX_THREADCOUNT
-        && len > ENCRYPTION_THREADCOUNT.load(std::sync::atomic::Ordering::Acquire)
+struct Shared {
+    config: WorkPoolConfig,
+    state: Mutex<State>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    queue: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+    spawned: usize,
+    shutdown: bool,
+    handles: Vec<JoinHandle<()>>,
+}
+
+static GLOBAL: once_cell::sync::Lazy<WorkPool> =
+    once_cell::sync::Lazy::new(|| WorkPool::new(WorkPoolConfig::default()));
+
+impl Default for WorkPool {
+    /// Returns a pool using `spawn_blocking` when running in a tokio runtime with
+    /// the `tokio` feature enabled, else the global pool.
+    fn default() -> Self {
+        #[cfg(feature = "tokio")]
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            return WorkPool::tokio(handle);
+        }
+        WorkPool::global()
+    }
+}
+
+impl WorkPool {
+    pub fn new(config: WorkPoolConfig) -> Self {
+        WorkPool(Kind::Threads(Arc::new(Shared {
+            config,
+            state: Mutex::new(State::default()),
+            condvar: Condvar::new(),
+        })))
+    }
+
+    /// The process-wide pool, created with the default configuration.
+    pub fn global() -> Self {
+        GLOBAL.clone()
+    }
+
+    /// A pool running its jobs using `spawn_blocking` on the given runtime.
+    #[cfg(feature = "tokio")]
+    pub fn tokio(handle: tokio::runtime::Handle) -> Self {
+        WorkPool(Kind::Tokio(handle))
+    }
+
+    /// Runs `job` on the pool, returning a receiver for its result.
+    ///
+    /// If the pool has been shut down, the receiver will be cancelled.
+    pub fn spawn<T, F>(&self, job: F) -> Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
     {
-        spawn_workthread()
+        let (send, recv) = futures_channel::oneshot::channel();
+        let job = move || {
+            if send.send(job()).is_err() {
+                eprintln!("async cancellation in workpool job");
+            }
+        };
+        match &self.0 {
+            Kind::Threads(shared) => shared.push(Box::new(job)),
+            #[cfg(feature = "tokio")]
+            Kind::Tokio(handle) => {
+                handle.spawn_blocking(job);
+            }
+        }
+        recv
+    }
+
+    /// Runs `job` on the pool and waits for its result.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, ShutDown>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        self.spawn(job).await.map_err(|_| ShutDown)
+    }
+
+    /// Stops accepting new jobs and waits for the threads to finish the queued ones.
+    ///
+    /// This blocks the current thread, pools using `spawn_blocking` are not affected.
+    pub fn shutdown(&self) {
+        let Some(shared) = self.shared() else {
+            return;
+        };
+        let handles = {
+            let mut state = shared.state.lock();
+            state.shutdown = true;
+            std::mem::take(&mut state.handles)
+        };
+        shared.condvar.notify_all();
+        for handle in handles {
+            if handle.join().is_err() {
+                eprintln!("workpool thread panicked");
+            }
+        }
     }
 
-    WORKTHREADS_CONDVAR.notify_one();
+    /// The amount of threads currently alive.
+    pub fn thread_count(&self) -> usize {
+        self.shared()
+            .map_or(0, |shared| shared.state.lock().threads)
+    }
 
-    recv
+    fn shared(&self) -> Option<&Arc<Shared>> {
+        match &self.0 {
+            Kind::Threads(shared) => Some(shared),
+            #[cfg(feature = "tokio")]
+            Kind::Tokio(_) => None,
+        }
+    }
 }
 
-fn spawn_workthread() {
-    static ID: AtomicUsize = AtomicUsize::new(0);
-    std::thread::Builder::new()
-        .name(format!(
-            "miners-encryption-{}",
-            ID.fetch_add(1, Ordering::Relaxed)
-        ))
-        .stack_size(8192) // This can probably be even less but if we get a stackoverflow this line is probably to blame
-        .spawn(|| loop {
-            let mut mutex = ENCRYPTION_WORKQUEUE.lock();
-            if mutex.is_empty() {
-                WORKTHREADS_CONDVAR.wait(&mut mutex);
+impl Shared {
+    fn push(self: &Arc<Self>, job: Job) {
+        let mut state = self.state.lock();
+        if state.shutdown {
+            // dropping the job cancels the receiver
+            return;
+        }
+        state.queue.push_back(job);
+        if state.idle >= state.queue.len() || state.threads >= self.config.max_threads {
+            drop(state);
+            self.condvar.notify_one();
+            return;
+        }
+        match self.spawn_thread(&mut state) {
+            Ok(handle) => {
+                state.handles.retain(|handle| !handle.is_finished());
+                state.handles.push(handle);
             }
-            if let Some(((mut buf, len_from_end, mut enc), send)) = mutex.pop_front() {
-                drop(mutex);
-                let start = buf.len() - len_from_end;
-                encrypt(&mut buf[start..], &mut enc);
-                if send.send((buf, enc)).is_err() {
-                    eprintln!("async cancellation in encryption workthread");
+            Err(e) => {
+                eprintln!("failed to spawn workpool thread: {e}");
+                if state.threads == 0 {
+                    // nobody is going to run the jobs, so do it ourselves
+                    let jobs = std::mem::take(&mut state.queue);
+                    drop(state);
+                    jobs.into_iter().for_each(|job| job());
                 }
             }
-        })
-        .unwrap();
-    ENCRYPTION_THREADCOUNT.fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+        }
+    }
+
+    fn spawn_thread(self: &Arc<Self>, state: &mut State) -> io::Result<JoinHandle<()>> {
+        let shared = self.clone();
+        let handle = std::thread::Builder::new()
+            .name(format!("{}-{}", self.config.name, state.spawned))
+            .stack_size(self.config.stack_size)
+            .spawn(move || shared.work())?;
+        state.spawned += 1;
+        state.threads += 1;
+        Ok(handle)
+    }
+
+    fn work(&self) {
+        let mut state = self.state.lock();
+        loop {
+            if let Some(job) = state.queue.pop_front() {
+                drop(state);
+                // a panicking job only cancels its receiver, the thread keeps working
+                let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job));
+                state = self.state.lock();
+                continue;
+            }
+            if state.shutdown {
+                break;
+            }
+            state.idle += 1;
+            let timed_out = match self.config.idle_timeout {
+                Some(timeout) => self.condvar.wait_for(&mut state, timeout).timed_out(),
+                None => {
+                    self.condvar.wait(&mut state);
+                    false
+                }
+            };
+            state.idle -= 1;
+            if timed_out && state.queue.is_empty() {
+                break;
+            }
+        }
+        state.threads -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::block_on;
+
+    #[test]
+    fn reap_and_shutdown() {
+        let pool = WorkPool::new(WorkPoolConfig {
+            max_threads: 2,
+            idle_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        });
+        assert_eq!(block_on(pool.run(|| 1 + 1)).unwrap(), 2);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(pool.thread_count(), 0);
+
+        let recv = pool.spawn(|| std::thread::sleep(Duration::from_millis(50)));
+        pool.shutdown();
+        // queued jobs are finished before shutting down
+        assert!(block_on(recv).is_ok());
+        assert!(block_on(pool.run(|| ())).is_err());
+    }
+
+    #[test]
+    fn panicking_job() {
+        let pool = WorkPool::new(WorkPoolConfig {
+            max_threads: 1,
+            ..Default::default()
+        });
+        for _ in 0..3 {
+            assert!(block_on(pool.run(|| panic!("job failed"))).is_err());
+        }
+        assert_eq!(block_on(pool.run(|| 1 + 1)).unwrap(), 2);
+        assert_eq!(pool.thread_count(), 1);
+    }
 }
@@ -53,3 +53,30 @@ pub trait PacketExt: Packet {
     }
 }
 impl<T: Packet> PacketExt for T {}
+
+/// The state of a connection, which determines the meaning of packet ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum State {
+    Handshaking,
+    Status,
+    Login,
+    Play,
+}
+
+/// The direction a packet is sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Sent from the server to the client.
+    Clientbound,
+    /// Sent from the client to the server.
+    Serverbound,
+}
+
+impl Direction {
+    pub fn opposite(self) -> Self {
+        match self {
+            Direction::Clientbound => Direction::Serverbound,
+            Direction::Serverbound => Direction::Clientbound,
+        }
+    }
+}
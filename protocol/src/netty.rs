@@ -4,3 +4,45 @@ pub mod handshaking;
 pub mod login;
 pub mod play;
 pub mod status;
+
+use miners_version::ProtocolVersion;
+
+use crate::*;
+
+/// A packet of any state and direction, as returned by `parse`.
+pub enum AnyPacket<'a> {
+    SbHandshaking(handshaking::SbHandshaking<'a>),
+    CbStatus(status::CbStatus<'a>),
+    SbStatus(status::SbStatus),
+    CbLogin(login::CbLogin<'a>),
+    SbLogin(login::SbLogin<'a>),
+    CbPlay(play::CbPlay<'a>),
+    SbPlay(play::SbPlay<'a>),
+}
+
+/// Parses a packet using the parser of the given state and direction.
+pub fn parse<'a>(
+    state: State,
+    direction: Direction,
+    packet: RawPacket<'a>,
+    version: ProtocolVersion,
+) -> Result<AnyPacket<'a>, decode::Error> {
+    use Direction::*;
+    Ok(match (state, direction) {
+        (State::Handshaking, Serverbound) => {
+            AnyPacket::SbHandshaking(handshaking::SbHandshaking::parse(packet, version)?)
+        }
+        // there are no clientbound handshaking packets
+        (State::Handshaking, Clientbound) => return Err(decode::Error::InvalidId),
+        (State::Status, Clientbound) => {
+            AnyPacket::CbStatus(status::CbStatus::parse(packet, version)?)
+        }
+        (State::Status, Serverbound) => {
+            AnyPacket::SbStatus(status::SbStatus::parse(packet, version)?)
+        }
+        (State::Login, Clientbound) => AnyPacket::CbLogin(login::CbLogin::parse(packet, version)?),
+        (State::Login, Serverbound) => AnyPacket::SbLogin(login::SbLogin::parse(packet, version)?),
+        (State::Play, Clientbound) => AnyPacket::CbPlay(play::CbPlay::parse(packet, version)?),
+        (State::Play, Serverbound) => AnyPacket::SbPlay(play::SbPlay::parse(packet, version)?),
+    })
+}
@@ -9,33 +9,26 @@ parsing_tree! {
     login_cb_custom login_cb_tree crate::netty::login::clientbound::;
     0x00 => {
         0..=12 => Disconnect0<'a>,
-        13..=384 => Disconnect0<'a>,
-        // 385..=390 => _385,
+        13..=760 => Disconnect0<'a>,
     },
     0x01 => {
         0..=18 => EncryptionRequest0<'a>, //EncryptionResponse0<'a>,
-        19..=384 => EncryptionRequest19<'a>, //EncryptionResponse19<'a>,
-        // 385..=390 => _385,
+        19..=760 => EncryptionRequest19<'a>, //EncryptionResponse19<'a>,
     },
     0x02 => {
         0..=4 => Success0<'a>,
         5 => Success5<'a>,
         6..=13 => Success0<'a>,
-        14..=384 => Success5<'a>,
-        // 385..=390 => _385,
-        // 391..=706 => _391,
+        14..=706 => Success5<'a>,
         // 707..=758 => _707,
         // 759..=760 => _759,
         // 1073741825..=1073741905 => _1073741825,
     },
     0x03 => {
-        27..=384 => SetCompression27,
-        // 385..=390 => _385,
-        // 391..=760 => _391,
+        27..=760 => SetCompression27,
     },
     0x04 => {
-        // 385..=390 => _385,
-        // 391..=760 => _391,
+        385..=760 => LoginPluginRequest385<'a>,
     }
 }
 login_cb_custom! {
@@ -71,9 +64,7 @@ impl<'a> CbLogin<'a> {
 parsing_tree! {
     login_sb_custom login_sb_tree crate::netty::login::serverbound::;
     0x00 => {
-        0..=384 => LoginStart0<'a>,
-        // 385..=390 => _385,
-        // 391..=758 => _391,
+        0..=758 => LoginStart0<'a>,
         // 759 => _759,
         // 760 => _760,
         // 1073741825..=1073741905 => _1073741825,
@@ -82,16 +73,12 @@ parsing_tree! {
     },
     0x01 => {
         0..=18 => EncryptionResponse0<'a>,
-        19..=384 => EncryptionResponse19<'a>,
-        // 385..=390 => _385,
-        // 391..=758 => _391,
+        19..=758 => EncryptionResponse19<'a>,
         // 759..=760 => _759,
         // 1073741825..=1073741905 => _1073741825,
     },
     0x02 => {
-        // 385..=390 => _385,
-        // 391..=758 => _391,
-        // 759..=760 => _759,
+        385..=760 => LoginPluginResponse385<'a>,
         // 1073741825..=1073741906 => _1073741825,
     }
 }
@@ -70,3 +70,12 @@ pub struct SetCompression27 {
     #[encoding(varint)]
     pub threshold: i32,
 }
+
+#[derive(Encoding, ToStatic, Debug)]
+pub struct LoginPluginRequest385<'a> {
+    #[encoding(varint)]
+    pub message_id: i32,
+    pub channel: Cow<'a, str>,
+    #[encoding(rest)]
+    pub data: Cow<'a, [u8]>,
+}
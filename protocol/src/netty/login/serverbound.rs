@@ -1,3 +1,8 @@
+use ::miners_encoding::{
+    attrs::{Rest, Var},
+    decode, encode, Decode, Encode,
+};
+
 use std::borrow::Cow;
 
 #[derive(Encoding, ToStatic, Debug)]
@@ -18,3 +23,35 @@ pub struct EncryptionResponse19<'a> {
     pub secret: Cow<'a, [u8]>,
     pub verify_token: Cow<'a, [u8]>,
 }
+
+#[derive(ToStatic, Debug)]
+pub struct LoginPluginResponse385<'a> {
+    pub message_id: i32,
+    /// `None` if the client doesn't understand the request
+    pub data: Option<Cow<'a, [u8]>>,
+}
+
+impl<'dec: 'a, 'a> Decode<'dec> for LoginPluginResponse385<'a> {
+    fn decode(buf: &mut std::io::Cursor<&'dec [u8]>) -> decode::Result<Self> {
+        let message_id = Var::decode(buf)?.into_inner();
+        let successful = bool::decode(buf)?;
+        Ok(Self {
+            message_id,
+            data: match successful {
+                true => Some(Cow::Borrowed(Rest::<&[u8]>::decode(buf)?.into_inner())),
+                false => None,
+            },
+        })
+    }
+}
+impl<'a> Encode for LoginPluginResponse385<'a> {
+    fn encode(&self, buf: &mut impl ::std::io::Write) -> encode::Result<()> {
+        let Self { message_id, data } = self;
+        Var::from(*message_id).encode(buf)?;
+        data.is_some().encode(buf)?;
+        if let Some(data) = data {
+            buf.write_all(data)?;
+        }
+        Ok(())
+    }
+}
@@ -3,8 +3,13 @@ use miners_version::ProtocolVersion;
 use crate::*;
 
 pub mod clientbound;
+#[cfg(feature = "json")]
+mod server_status;
 pub mod serverbound;
 
+#[cfg(feature = "json")]
+pub use server_status::*;
+
 parsing_tree! {
     status_cb_custom status_cb_tree crate::netty::status::clientbound::;
     0x00 => {
@@ -2,7 +2,7 @@ use std::borrow::Cow;
 
 #[derive(Encoding, ToStatic, Debug)]
 pub struct Response0<'a> {
-    // TODO: json thing
+    /// The JSON of the status, parsed by `status()` with the `json` feature.
     pub data: Cow<'a, str>,
 }
 
@@ -0,0 +1,175 @@
+//! The JSON sent in the status `Response0`.
+
+use base64::Engine;
+use miners_chat::Component;
+use serde_derive::{Deserialize, Serialize};
+
+use super::clientbound::Response0;
+
+const FAVICON_PREFIX: &str = "data:image/png;base64,";
+
+/// The status of a server as shown in the server list.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerStatus {
+    pub version: StatusVersion,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub players: Option<StatusPlayers>,
+    #[serde(default)]
+    pub description: Component,
+    /// A png as data uri, see `favicon_png`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub favicon: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enforces_secure_chat: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previews_chat: Option<bool>,
+    /// The mods of Forge servers up to 1.12.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modinfo: Option<ModInfo>,
+    /// The mods and channels of Forge servers from 1.13 on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forge_data: Option<ForgeData>,
+    /// Any other fields, as sent by modded servers.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusVersion {
+    pub name: String,
+    pub protocol: i32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusPlayers {
+    pub max: i32,
+    pub online: i32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sample: Vec<PlayerSample>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlayerSample {
+    pub name: String,
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModInfo {
+    /// `FML` for Forge servers.
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(rename = "modList", default)]
+    pub mod_list: Vec<ModInfoEntry>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModInfoEntry {
+    pub modid: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForgeData {
+    #[serde(default)]
+    pub channels: Vec<ForgeChannel>,
+    #[serde(default)]
+    pub mods: Vec<ForgeMod>,
+    #[serde(default)]
+    pub fml_network_version: i32,
+    /// Whether the lists have been cut short to fit the response.
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ForgeChannel {
+    pub res: String,
+    pub version: String,
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ForgeMod {
+    pub mod_id: String,
+    pub modmarker: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FaviconError {
+    #[error("the favicon is not a png data uri")]
+    NotPng,
+    #[error(transparent)]
+    Base64(#[from] base64::DecodeError),
+}
+
+impl ServerStatus {
+    /// Decodes the png of the favicon.
+    pub fn favicon_png(&self) -> Option<Result<Vec<u8>, FaviconError>> {
+        let favicon = self.favicon.as_ref()?;
+        let Some(data) = favicon.strip_prefix(FAVICON_PREFIX) else {
+            return Some(Err(FaviconError::NotPng));
+        };
+        // older servers break the base64 into lines
+        let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+        Some(
+            base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .map_err(Into::into),
+        )
+    }
+
+    /// Sets the favicon to a png, which should be 64x64 pixels.
+    pub fn set_favicon_png(&mut self, png: &[u8]) {
+        let data = base64::engine::general_purpose::STANDARD.encode(png);
+        self.favicon = Some(format!("{FAVICON_PREFIX}{data}"));
+    }
+}
+
+impl<'a> Response0<'a> {
+    pub fn status(&self) -> serde_json::Result<ServerStatus> {
+        serde_json::from_str(&self.data)
+    }
+
+    pub fn from_status(status: &ServerStatus) -> Response0<'static> {
+        Response0 {
+            data: serde_json::to_string(status)
+                .expect("the status serializes to json")
+                .into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let response = Response0 {
+            data: r#"{
+                "version": {"name": "1.12.2", "protocol": 340},
+                "players": {"max": 20, "online": 1, "sample": [{"name": "steve", "id": "069a79f4-44e9-4726-a5be-fca90e38aaf5"}]},
+                "description": {"text": "A ", "extra": [{"text": "server", "bold": true}]},
+                "favicon": "data:image/png;base64,iVBO\nRw0KGgo=",
+                "modinfo": {"type": "FML", "modList": [{"modid": "forge", "version": "14.23.5.2859"}]},
+                "custom": 1
+            }"#
+            .into(),
+        };
+        let status = response.status().unwrap();
+        assert_eq!(status.version.protocol, 340);
+        let players = status.players.as_ref().unwrap();
+        assert_eq!(players.sample[0].name, "steve");
+        assert_eq!(status.description.to_plain(), "A server");
+        assert_eq!(status.favicon_png().unwrap().unwrap(), b"\x89PNG\r\n\x1a\n");
+        assert_eq!(status.modinfo.as_ref().unwrap().mod_list[0].modid, "forge");
+        assert_eq!(status.extra["custom"], 1);
+
+        let reencoded = Response0::from_status(&status).status().unwrap();
+        assert_eq!(reencoded, status);
+    }
+}
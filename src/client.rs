@@ -0,0 +1,386 @@
+//! Logging into servers.
+//!
+//! `connect` opens a connection and performs the login, returning the
+//! connection in the play state. `login` does the same on top of any
+//! transport, for example one already connected through a proxy.
+//!
+//! `ping_server` requests the status shown in the server list instead,
+//! `ping_server_legacy` does so the way clients before 1.7 did.
+
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_lite::io::{BufReader, BufWriter};
+use futures_lite::{AsyncRead, AsyncWrite};
+use miners_auth::{Auth, HttpClient};
+use miners_net::crypto::{self, CryptoError, PublicKey};
+use miners_net::legacy::{LegacyPing, LegacyStatus, LEGACY_PROTOCOL};
+use miners_protocol::netty::handshaking::serverbound::{Handshake0, NextState0};
+use miners_protocol::netty::handshaking::SbHandshaking;
+use miners_protocol::netty::login::serverbound::{
+    EncryptionResponse0, EncryptionResponse19, LoginPluginResponse385, LoginStart0,
+};
+use miners_protocol::netty::login::{CbLogin, SbLogin};
+use miners_protocol::netty::status::serverbound::{Ping0, Request0};
+use miners_protocol::netty::status::{CbStatus, SbStatus, ServerStatus};
+use miners_version::ProtocolVersion;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio_util::compat::Compat;
+
+use crate::conn::{ClientConnection, Next, Play};
+
+const DEFAULT_PORT: u16 = 25565;
+
+/// The account to log in with.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    /// The session of an authenticated account, required by online mode servers.
+    pub auth: Option<Auth>,
+}
+
+impl Profile {
+    pub fn offline(name: impl Into<String>) -> Self {
+        Profile {
+            name: name.into(),
+            auth: None,
+        }
+    }
+}
+
+impl From<Auth> for Profile {
+    fn from(auth: Auth) -> Self {
+        Profile {
+            name: auth.name.clone(),
+            auth: Some(auth),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Connection(#[from] crate::conn::Error),
+    #[error("disconnected during login: {0}")]
+    Disconnected(String),
+    #[error("the server is in online mode, but the profile is not authenticated")]
+    OnlineMode,
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error("joining the server failed: {0}")]
+    Auth(#[from] miners_auth::Error),
+    #[error("invalid status: {0}")]
+    Status(#[from] serde_json::Error),
+    #[error("the server sent an unexpected packet")]
+    UnexpectedPacket,
+}
+
+pub type TcpConnection =
+    ClientConnection<Play, BufReader<Compat<OwnedReadHalf>>, BufWriter<Compat<OwnedWriteHalf>>>;
+
+/// Connects to `addr`, which is a host with an optional port, and logs in.
+pub async fn connect(
+    addr: &str,
+    version: ProtocolVersion,
+    profile: Profile,
+) -> Result<TcpConnection, Error> {
+    let (host, port) = split_addr(addr);
+    let conn = connect_tcp(host, port).await?;
+    login(conn, host, port, version, &profile, &reqwest::Client::new()).await
+}
+
+pub(crate) fn split_addr(addr: &str) -> (&str, u16) {
+    let (host, port) = match addr.rsplit_once(':') {
+        Some((host, port)) if !host.ends_with(':') => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => (addr, DEFAULT_PORT),
+        },
+        _ => (addr, DEFAULT_PORT),
+    };
+    (host.trim_start_matches('[').trim_end_matches(']'), port)
+}
+
+pub(crate) async fn connect_tcp(
+    host: &str,
+    port: u16,
+) -> io::Result<
+    miners_net::conn::Connection<
+        BufReader<Compat<OwnedReadHalf>>,
+        BufWriter<Compat<OwnedWriteHalf>>,
+    >,
+> {
+    let stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(true)?;
+    Ok(miners_net::conn::Connection::from_tcp_stream(stream))
+}
+
+/// Logs in on an established connection, `host` and `port` are sent in the
+/// handshake and `http` is used to join online mode servers.
+pub async fn login<R, W>(
+    conn: miners_net::conn::Connection<R, W>,
+    host: &str,
+    port: u16,
+    version: ProtocolVersion,
+    profile: &Profile,
+    http: &impl HttpClient,
+) -> Result<ClientConnection<Play, R, W>, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut conn = ClientConnection::new(conn, version);
+    conn.write(SbHandshaking::Handshake0(Handshake0 {
+        protocol_version: *version,
+        server_address: host.into(),
+        server_port: port,
+        next_state: NextState0::Login,
+    }))
+    .await?;
+    let Ok(Next::Login(mut conn)) = conn.next() else {
+        unreachable!("the handshake moves on to login")
+    };
+    conn.write(SbLogin::LoginStart0(LoginStart0 {
+        username: profile.name.as_str().into(),
+    }))
+    .await?;
+    conn.flush().await?;
+
+    loop {
+        let (server_id, public_key, verify_token) = match conn.read().await? {
+            CbLogin::Disconnect0(disconnect) => {
+                return Err(Error::Disconnected(disconnect.reason.into_owned()))
+            }
+            CbLogin::EncryptionRequest0(request) => (
+                request.server_id.into_owned(),
+                request.public_key.into_owned(),
+                request.verify_token.into_owned(),
+            ),
+            CbLogin::EncryptionRequest19(request) => (
+                request.server_id.into_owned(),
+                request.public_key.into_owned(),
+                request.verify_token.into_owned(),
+            ),
+            // applied by the connection
+            CbLogin::SetCompression27(_) => continue,
+            // like vanilla, no plugin messages are understood
+            CbLogin::LoginPluginRequest385(request) => {
+                let message_id = request.message_id;
+                conn.write(SbLogin::LoginPluginResponse385(LoginPluginResponse385 {
+                    message_id,
+                    data: None,
+                }))
+                .await?;
+                conn.flush().await?;
+                continue;
+            }
+            CbLogin::Success0(_) | CbLogin::Success5(_) => break,
+        };
+
+        let auth = profile.auth.as_ref().ok_or(Error::OnlineMode)?;
+        let key = PublicKey::from_der(&public_key)?;
+        let shared_secret = crypto::generate_shared_secret();
+        let hash = crypto::server_hash(&server_id, &shared_secret, &public_key);
+        auth.join(&hash, http).await?;
+
+        let secret = key.encrypt(&shared_secret)?;
+        let verify_token = key.encrypt(&verify_token)?;
+        let response = if *version < 19 {
+            SbLogin::EncryptionResponse0(EncryptionResponse0 {
+                secret: secret.into(),
+                verify_token: verify_token.into(),
+            })
+        } else {
+            SbLogin::EncryptionResponse19(EncryptionResponse19 {
+                secret: secret.into(),
+                verify_token: verify_token.into(),
+            })
+        };
+        conn.write_encryption_response(response, &shared_secret)
+            .await?;
+    }
+    let Ok(conn) = conn.into_play() else {
+        unreachable!("the login has succeeded")
+    };
+    Ok(conn)
+}
+
+/// The answer to a server list ping.
+#[derive(Debug, Clone)]
+pub struct Ping {
+    pub status: ServerStatus,
+    /// The round trip time of the ping packet.
+    pub latency: Duration,
+}
+
+/// Connects to `addr`, which is a host with an optional port, and requests
+/// its status.
+pub async fn ping_server(addr: &str, version: ProtocolVersion) -> Result<Ping, Error> {
+    let (host, port) = split_addr(addr);
+    let conn = connect_tcp(host, port).await?;
+    ping(conn, host, port, version).await
+}
+
+/// Requests the status on an established connection, `host` and `port` are
+/// sent in the handshake.
+pub async fn ping<R, W>(
+    conn: miners_net::conn::Connection<R, W>,
+    host: &str,
+    port: u16,
+    version: ProtocolVersion,
+) -> Result<Ping, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut conn = ClientConnection::new(conn, version);
+    conn.write(SbHandshaking::Handshake0(Handshake0 {
+        protocol_version: *version,
+        server_address: host.into(),
+        server_port: port,
+        next_state: NextState0::Status,
+    }))
+    .await?;
+    let Ok(Next::Status(mut conn)) = conn.next() else {
+        unreachable!("the handshake moves on to status")
+    };
+    conn.write(SbStatus::Request0(Request0 {})).await?;
+    conn.flush().await?;
+    let status = match conn.read().await? {
+        CbStatus::Response0(response) => response.status()?,
+        _ => return Err(Error::UnexpectedPacket),
+    };
+
+    // like vanilla, the payload is the current time in milliseconds
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_millis() as i64);
+    let start = Instant::now();
+    conn.write(SbStatus::Ping0(Ping0 { time })).await?;
+    conn.flush().await?;
+    match conn.read().await? {
+        CbStatus::Ping0(pong) if pong.time == time => {}
+        _ => return Err(Error::UnexpectedPacket),
+    }
+    Ok(Ping {
+        status,
+        latency: start.elapsed(),
+    })
+}
+
+/// Connects to `addr`, which is a host with an optional port, and requests
+/// its status with the legacy ping of 1.6 clients.
+///
+/// Servers from before 1.7 only understand this ping, newer ones still answer it.
+pub async fn ping_server_legacy(addr: &str) -> Result<LegacyStatus, Error> {
+    let (host, port) = split_addr(addr);
+    let conn = connect_tcp(host, port).await?;
+    ping_legacy(conn, host, port).await
+}
+
+/// Sends a legacy ping on an established connection and reads the answer,
+/// `host` and `port` are sent in the ping.
+pub async fn ping_legacy<R, W>(
+    mut conn: miners_net::conn::Connection<R, W>,
+    host: &str,
+    port: u16,
+) -> Result<LegacyStatus, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let ping = LegacyPing::V1_6 {
+        protocol: LEGACY_PROTOCOL,
+        host: host.into(),
+        port: port.into(),
+    };
+    conn.write_half.write_legacy_ping(&ping).await?;
+    conn.write_half.flush().await?;
+    let status = conn
+        .read_half
+        .read_legacy_status()
+        .await
+        .map_err(crate::conn::Error::from)?;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conn::ServerConnection;
+    use futures_lite::future::{block_on, zip};
+    use miners_net::memory::duplex;
+    use miners_protocol::netty::login::clientbound::{SetCompression27, Success5};
+
+    #[test]
+    fn offline_login() {
+        let version = ProtocolVersion::new(47).unwrap();
+        let (client, server) = duplex(1024);
+        let client = async {
+            let profile = Profile::offline("steve");
+            let http = reqwest::Client::new();
+            login(client, "localhost", 25565, version, &profile, &http)
+                .await
+                .unwrap()
+        };
+        let server = async {
+            let mut server = ServerConnection::new(server, version);
+            server.read().await.unwrap();
+            let Ok(Next::Login(mut server)) = server.next() else {
+                panic!("didn't move on to login")
+            };
+            let name = match server.read().await.unwrap() {
+                SbLogin::LoginStart0(start) => start.username.into_owned(),
+                _ => panic!("expected LoginStart"),
+            };
+            server
+                .write(CbLogin::SetCompression27(SetCompression27 {
+                    threshold: 16,
+                }))
+                .await
+                .unwrap();
+            server
+                .write(CbLogin::Success5(Success5 {
+                    uuid: None,
+                    username: name.into(),
+                }))
+                .await
+                .unwrap();
+        };
+        let (client, _) = block_on(zip(client, server));
+        assert_eq!(client.into_inner().read_half.stats().packets, 2);
+    }
+
+    #[test]
+    fn online_without_auth() {
+        let version = ProtocolVersion::new(47).unwrap();
+        let (client, server) = duplex(1024);
+        let client = async {
+            let profile = Profile::offline("steve");
+            let http = reqwest::Client::new();
+            login(client, "localhost", 25565, version, &profile, &http).await
+        };
+        let server = async {
+            let mut server = ServerConnection::new(server, version);
+            server.read().await.unwrap();
+            let Ok(Next::Login(mut server)) = server.next() else {
+                panic!("didn't move on to login")
+            };
+            server.read().await.unwrap();
+            server
+                .write(CbLogin::EncryptionRequest19(
+                    miners_protocol::netty::login::clientbound::EncryptionRequest19 {
+                        server_id: "".into(),
+                        public_key: vec![].into(),
+                        verify_token: vec![1, 2, 3, 4].into(),
+                    },
+                ))
+                .await
+                .unwrap();
+        };
+        let (res, _) = block_on(zip(client, server));
+        assert!(matches!(res, Err(Error::OnlineMode)));
+    }
+}
@@ -0,0 +1,613 @@
+//! Connections which know their protocol state.
+//!
+//! A `Connection<S, Side, R, W>` only reads and writes the packets of the
+//! state `S` as seen from `Side`, parsing them for the protocol version of
+//! the connection. Transitions consume the connection and return it typed
+//! for the next state, and the packets changing the framing are applied as
+//! they pass: `SetCompression27` enables compression once it has been read
+//! or written, and encryption is enabled right after the `EncryptionResponse`.
+
+use std::marker::PhantomData;
+
+use futures_lite::{AsyncRead, AsyncWrite};
+use miners_encoding::{decode, encode};
+use miners_net::codec::{EncryptionError, ReadError};
+use miners_net::encoding::Encoder;
+use miners_packet::{Direction, Packet, RawPacket, State};
+use miners_protocol::netty::handshaking::{serverbound::NextState0, SbHandshaking};
+use miners_protocol::netty::login::{CbLogin, SbLogin};
+use miners_protocol::netty::play::{CbPlay, SbPlay};
+use miners_protocol::netty::status::{CbStatus, SbStatus};
+use miners_version::ProtocolVersion;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The errors of typed connections.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Read(#[from] ReadError),
+    #[error("failed to decode packet: {0}")]
+    Decode(#[from] decode::Error),
+    #[error("failed to encode packet: {0}")]
+    Encode(#[from] encode::Error),
+    #[error("the packet doesn't exist in protocol version {0}")]
+    Unsupported(ProtocolVersion),
+    #[error(transparent)]
+    Encryption(#[from] EncryptionError),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Read(e.into())
+    }
+}
+
+/// What a packet changes about the connection it passes through.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy)]
+pub enum Effect {
+    None,
+    Compression(i32),
+    Handshake { version: i32, next: State },
+    Finished,
+}
+
+/// A protocol state and the packets sent in it.
+pub trait ProtocolState: sealed::Sealed {
+    const STATE: State;
+    type Clientbound<'a>: Packet;
+    type Serverbound<'a>: Packet;
+
+    fn parse_clientbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<Self::Clientbound<'_>>;
+    fn parse_serverbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<Self::Serverbound<'_>>;
+
+    #[doc(hidden)]
+    fn clientbound_effect(_packet: &Self::Clientbound<'_>) -> Effect {
+        Effect::None
+    }
+    #[doc(hidden)]
+    fn serverbound_effect(_packet: &Self::Serverbound<'_>) -> Effect {
+        Effect::None
+    }
+}
+
+/// The side of a connection, which determines the direction
+/// of the packets read and written.
+pub trait Side: sealed::Sealed {
+    const INCOMING: Direction;
+    type Incoming<'a, S: ProtocolState>: Packet;
+    type Outgoing<'a, S: ProtocolState>: Packet;
+
+    fn parse<S: ProtocolState>(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<Self::Incoming<'_, S>>;
+
+    #[doc(hidden)]
+    fn incoming_effect<S: ProtocolState>(packet: &Self::Incoming<'_, S>) -> Effect;
+    #[doc(hidden)]
+    fn outgoing_effect<S: ProtocolState>(packet: &Self::Outgoing<'_, S>) -> Effect;
+}
+
+/// The client side, reading clientbound and writing serverbound packets.
+pub enum Client {}
+/// The server side, reading serverbound and writing clientbound packets.
+pub enum Server {}
+
+impl sealed::Sealed for Client {}
+impl sealed::Sealed for Server {}
+
+impl Side for Client {
+    const INCOMING: Direction = Direction::Clientbound;
+    type Incoming<'a, S: ProtocolState> = S::Clientbound<'a>;
+    type Outgoing<'a, S: ProtocolState> = S::Serverbound<'a>;
+
+    fn parse<S: ProtocolState>(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<Self::Incoming<'_, S>> {
+        S::parse_clientbound(packet, version)
+    }
+    fn incoming_effect<S: ProtocolState>(packet: &Self::Incoming<'_, S>) -> Effect {
+        S::clientbound_effect(packet)
+    }
+    fn outgoing_effect<S: ProtocolState>(packet: &Self::Outgoing<'_, S>) -> Effect {
+        S::serverbound_effect(packet)
+    }
+}
+
+impl Side for Server {
+    const INCOMING: Direction = Direction::Serverbound;
+    type Incoming<'a, S: ProtocolState> = S::Serverbound<'a>;
+    type Outgoing<'a, S: ProtocolState> = S::Clientbound<'a>;
+
+    fn parse<S: ProtocolState>(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<Self::Incoming<'_, S>> {
+        S::parse_serverbound(packet, version)
+    }
+    fn incoming_effect<S: ProtocolState>(packet: &Self::Incoming<'_, S>) -> Effect {
+        S::serverbound_effect(packet)
+    }
+    fn outgoing_effect<S: ProtocolState>(packet: &Self::Outgoing<'_, S>) -> Effect {
+        S::clientbound_effect(packet)
+    }
+}
+
+/// There are no clientbound packets while handshaking.
+#[derive(Debug)]
+pub enum NoPacket {}
+
+impl Packet for NoPacket {
+    fn id_for_version(&self, _version: ProtocolVersion) -> Option<i32> {
+        match *self {}
+    }
+
+    fn encode_for_version(
+        &self,
+        _version: ProtocolVersion,
+        _writer: &mut impl std::io::Write,
+    ) -> Option<encode::Result<()>> {
+        match *self {}
+    }
+}
+
+pub enum Handshaking {}
+pub enum Status {}
+pub enum Login {}
+pub enum Play {}
+
+impl sealed::Sealed for Handshaking {}
+impl sealed::Sealed for Status {}
+impl sealed::Sealed for Login {}
+impl sealed::Sealed for Play {}
+
+impl ProtocolState for Handshaking {
+    const STATE: State = State::Handshaking;
+    type Clientbound<'a> = NoPacket;
+    type Serverbound<'a> = SbHandshaking<'a>;
+
+    fn parse_clientbound(_: RawPacket<'_>, _: ProtocolVersion) -> decode::Result<NoPacket> {
+        Err(decode::Error::InvalidId)
+    }
+    fn parse_serverbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<SbHandshaking<'_>> {
+        SbHandshaking::parse(packet, version)
+    }
+
+    fn serverbound_effect(packet: &SbHandshaking<'_>) -> Effect {
+        let SbHandshaking::Handshake0(handshake) = packet;
+        Effect::Handshake {
+            version: handshake.protocol_version,
+            next: match handshake.next_state {
+                NextState0::Status => State::Status,
+                NextState0::Login => State::Login,
+            },
+        }
+    }
+}
+
+impl ProtocolState for Status {
+    const STATE: State = State::Status;
+    type Clientbound<'a> = CbStatus<'a>;
+    type Serverbound<'a> = SbStatus;
+
+    fn parse_clientbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<CbStatus<'_>> {
+        CbStatus::parse(packet, version)
+    }
+    fn parse_serverbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<SbStatus> {
+        SbStatus::parse(packet, version)
+    }
+}
+
+impl ProtocolState for Login {
+    const STATE: State = State::Login;
+    type Clientbound<'a> = CbLogin<'a>;
+    type Serverbound<'a> = SbLogin<'a>;
+
+    fn parse_clientbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<CbLogin<'_>> {
+        CbLogin::parse(packet, version)
+    }
+    fn parse_serverbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<SbLogin<'_>> {
+        SbLogin::parse(packet, version)
+    }
+
+    fn clientbound_effect(packet: &CbLogin<'_>) -> Effect {
+        match packet {
+            CbLogin::SetCompression27(compression) => Effect::Compression(compression.threshold),
+            CbLogin::Success0(_) | CbLogin::Success5(_) => Effect::Finished,
+            _ => Effect::None,
+        }
+    }
+}
+
+impl ProtocolState for Play {
+    const STATE: State = State::Play;
+    type Clientbound<'a> = CbPlay<'a>;
+    type Serverbound<'a> = SbPlay<'a>;
+
+    fn parse_clientbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<CbPlay<'_>> {
+        CbPlay::parse(packet, version)
+    }
+    fn parse_serverbound(
+        packet: RawPacket<'_>,
+        version: ProtocolVersion,
+    ) -> decode::Result<SbPlay<'_>> {
+        SbPlay::parse(packet, version)
+    }
+}
+
+/// A connection in the protocol state `S`, seen from `Si`.
+pub struct Connection<S, Si, R, W> {
+    inner: miners_net::conn::Connection<R, W>,
+    version: ProtocolVersion,
+    encoder: Encoder,
+    /// the effect of the last packet read, applied before the next
+    /// operation as the packet borrows the read half until then
+    pending: Effect,
+    /// the state the last packet has moved on to
+    next: Option<State>,
+    _state: PhantomData<(S, Si)>,
+}
+
+pub type ClientConnection<S, R, W> = Connection<S, Client, R, W>;
+pub type ServerConnection<S, R, W> = Connection<S, Server, R, W>;
+
+impl<S, Si, R, W> Connection<S, Si, R, W> {
+    fn transition<T>(self) -> Connection<T, Si, R, W> {
+        Connection {
+            inner: self.inner,
+            version: self.version,
+            encoder: self.encoder,
+            pending: self.pending,
+            next: None,
+            _state: PhantomData,
+        }
+    }
+
+    fn apply(&mut self, effect: Effect) {
+        match effect {
+            Effect::None => {}
+            Effect::Compression(threshold) => {
+                self.inner.read_half.enable_compression(threshold);
+                self.inner.write_half.enable_compression(threshold);
+            }
+            Effect::Handshake { version, next } => {
+                // unknown versions are kept, so servers can still respond to them
+                if let Ok(version) = ProtocolVersion::new(version) {
+                    self.version = version;
+                }
+                self.next = Some(next);
+                self.capture_state(next);
+            }
+            Effect::Finished => {
+                self.next = Some(State::Play);
+                self.capture_state(State::Play);
+            }
+        }
+    }
+
+    /// Records the packets after a transition in `state`.
+    fn capture_state(&self, state: State) {
+        let read = self.inner.read_half.capture();
+        let write = self.inner.write_half.capture();
+        for capture in read.into_iter().chain(write) {
+            capture.set_state(state);
+            capture.set_version(self.version);
+        }
+    }
+
+    fn apply_pending(&mut self) {
+        let pending = std::mem::replace(&mut self.pending, Effect::None);
+        self.apply(pending);
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// The untyped connection, for anything not covered by the typed one.
+    pub fn inner_mut(&mut self) -> &mut miners_net::conn::Connection<R, W> {
+        self.apply_pending();
+        &mut self.inner
+    }
+
+    pub fn into_inner(mut self) -> miners_net::conn::Connection<R, W> {
+        self.apply_pending();
+        self.inner
+    }
+}
+
+impl<Si, R, W> Connection<Handshaking, Si, R, W> {
+    /// Wraps a new connection, servers pass any version which will
+    /// be replaced by the one of the client's handshake.
+    pub fn new(inner: miners_net::conn::Connection<R, W>, version: ProtocolVersion) -> Self {
+        Connection {
+            inner,
+            version,
+            encoder: Encoder::new(),
+            pending: Effect::None,
+            next: None,
+            _state: PhantomData,
+        }
+    }
+}
+
+impl<S, Si, R, W> Connection<S, Si, R, W>
+where
+    S: ProtocolState,
+    Si: Side,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Reads the next packet of this state.
+    pub async fn read(&mut self) -> Result<Si::Incoming<'_, S>, Error> {
+        self.apply_pending();
+        let encoded = self.inner.read_half.read_encoded().await?;
+        let packet = Si::parse::<S>(encoded.into_packet()?, self.version)?;
+        self.pending = Si::incoming_effect::<S>(&packet);
+        Ok(packet)
+    }
+
+    /// Writes a packet of this state, the writer still has to be flushed.
+    pub async fn write(&mut self, packet: Si::Outgoing<'_, S>) -> Result<(), Error> {
+        self.apply_pending();
+        if packet.id_for_version(self.version).is_none() {
+            return Err(Error::Unsupported(self.version));
+        }
+        self.inner
+            .write_half
+            .write_packet(self.version, &packet, &mut self.encoder)
+            .await?;
+        self.apply(Si::outgoing_effect::<S>(&packet));
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.write_half.flush().await
+    }
+}
+
+/// The state following the handshake.
+pub enum Next<Si, R, W> {
+    Status(Connection<Status, Si, R, W>),
+    Login(Connection<Login, Si, R, W>),
+}
+
+impl<Si, R, W> Connection<Handshaking, Si, R, W> {
+    /// Moves on to the state requested by the handshake,
+    /// returns the connection unchanged if there was none yet.
+    #[allow(clippy::result_large_err)]
+    pub fn next(mut self) -> Result<Next<Si, R, W>, Self> {
+        self.apply_pending();
+        match self.next {
+            Some(State::Status) => Ok(Next::Status(self.transition())),
+            Some(State::Login) => Ok(Next::Login(self.transition())),
+            _ => Err(self),
+        }
+    }
+}
+
+impl<Si, R, W> Connection<Login, Si, R, W> {
+    /// Enables encryption on both halves, servers call this after reading
+    /// the `EncryptionResponse` and decrypting the shared secret.
+    pub fn enable_encryption(&mut self, shared_secret: &[u8; 16]) -> Result<(), Error> {
+        self.apply_pending();
+        let inner = &mut self.inner;
+        inner.read_half.enable_encryption(shared_secret)?;
+        inner
+            .write_half
+            .enable_encryption(shared_secret)
+            .map_err(EncryptionError::from)?;
+        Ok(())
+    }
+
+    /// Moves on to play once the login `Success` has been read or written,
+    /// returns the connection unchanged otherwise.
+    #[allow(clippy::result_large_err)]
+    pub fn into_play(mut self) -> Result<Connection<Play, Si, R, W>, Self> {
+        self.apply_pending();
+        match self.next {
+            Some(State::Play) => Ok(self.transition()),
+            _ => Err(self),
+        }
+    }
+}
+
+impl<R, W> Connection<Login, Client, R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Writes the `EncryptionResponse` and enables encryption with the
+    /// shared secret it contains in encrypted form.
+    pub async fn write_encryption_response(
+        &mut self,
+        response: SbLogin<'_>,
+        shared_secret: &[u8; 16],
+    ) -> Result<(), Error> {
+        self.write(response).await?;
+        self.flush().await?;
+        self.enable_encryption(shared_secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_lite::future::{block_on, zip};
+    use miners_net::capture::{Capture, CaptureReader};
+    use miners_net::memory::{duplex, SharedBuffer};
+    use miners_protocol::netty::handshaking::serverbound::Handshake0;
+    use miners_protocol::netty::login::clientbound::{SetCompression27, Success5};
+    use miners_protocol::netty::login::serverbound::LoginStart0;
+    use miners_protocol::netty::play::serverbound::KeepAlive7;
+
+    #[test]
+    fn login() {
+        let version = ProtocolVersion::new(47).unwrap();
+        let (client, server) = duplex(1024);
+        let mut client = ClientConnection::new(client, version);
+        let mut server = ServerConnection::new(server, ProtocolVersion::new(5).unwrap());
+
+        let client = async {
+            client
+                .write(SbHandshaking::Handshake0(Handshake0 {
+                    protocol_version: 47,
+                    server_address: "localhost".into(),
+                    server_port: 25565,
+                    next_state: NextState0::Login,
+                }))
+                .await
+                .unwrap();
+            let Ok(Next::Login(mut client)) = client.next() else {
+                panic!("didn't move on to login")
+            };
+            client
+                .write(SbLogin::LoginStart0(LoginStart0 {
+                    username: "steve".into(),
+                }))
+                .await
+                .unwrap();
+            assert!(matches!(
+                client.read().await.unwrap(),
+                CbLogin::SetCompression27(_)
+            ));
+            assert!(matches!(client.read().await.unwrap(), CbLogin::Success5(_)));
+            client.into_play().ok().unwrap()
+        };
+        let server = async {
+            server.read().await.unwrap();
+            let Ok(Next::Login(mut server)) = server.next() else {
+                panic!("didn't move on to login")
+            };
+            assert_eq!(*server.version(), 47);
+            match server.read().await.unwrap() {
+                SbLogin::LoginStart0(start) => assert_eq!(start.username, "steve"),
+                _ => panic!("expected LoginStart"),
+            }
+            server
+                .write(CbLogin::SetCompression27(SetCompression27 { threshold: 0 }))
+                .await
+                .unwrap();
+            // compression alone doesn't finish the login
+            let mut server = server.into_play().err().unwrap();
+            server
+                .write(CbLogin::Success5(Success5 {
+                    uuid: None,
+                    username: "steve".into(),
+                }))
+                .await
+                .unwrap();
+            server.into_play().ok().unwrap()
+        };
+        let (client, server) = block_on(zip(client, server));
+        let client = client.into_inner();
+        let server = server.into_inner();
+        assert_eq!(client.read_half.stats().packets, 2);
+        assert_eq!(server.write_half.stats().packets, 2);
+    }
+
+    #[test]
+    fn capture_follows_transitions() {
+        let initial = ProtocolVersion::new(5).unwrap();
+        let (client, server) = duplex(1024);
+        let mut client = ClientConnection::new(client, ProtocolVersion::new(47).unwrap());
+        let mut server = ServerConnection::new(server, initial);
+        let file = SharedBuffer::default();
+        let capture = Capture::new(file.clone(), State::Handshaking, initial).unwrap();
+        server
+            .inner_mut()
+            .set_capture(capture.clone(), Direction::Serverbound);
+
+        let client = async {
+            client
+                .write(SbHandshaking::Handshake0(Handshake0 {
+                    protocol_version: 47,
+                    server_address: "localhost".into(),
+                    server_port: 25565,
+                    next_state: NextState0::Login,
+                }))
+                .await
+                .unwrap();
+            let Ok(Next::Login(mut client)) = client.next() else {
+                panic!("didn't move on to login")
+            };
+            client
+                .write(SbLogin::LoginStart0(LoginStart0 {
+                    username: "steve".into(),
+                }))
+                .await
+                .unwrap();
+            client.read().await.unwrap();
+            let mut client = client.into_play().ok().unwrap();
+            client
+                .write(SbPlay::KeepAlive7(KeepAlive7 { id: 1 }))
+                .await
+                .unwrap();
+        };
+        let server = async {
+            server.read().await.unwrap();
+            let Ok(Next::Login(mut server)) = server.next() else {
+                panic!("didn't move on to login")
+            };
+            server.read().await.unwrap();
+            server
+                .write(CbLogin::Success5(Success5 {
+                    uuid: None,
+                    username: "steve".into(),
+                }))
+                .await
+                .unwrap();
+            let mut server = server.into_play().ok().unwrap();
+            server.read().await.unwrap();
+        };
+        block_on(zip(client, server));
+        capture.flush().unwrap();
+
+        let file = file.contents();
+        let records = CaptureReader::new(&file[..])
+            .unwrap()
+            .map(|record| {
+                let record = record.unwrap();
+                (record.state, *record.version)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            [
+                (State::Handshaking, 5),
+                (State::Login, 47),
+                (State::Login, 47),
+                (State::Play, 47),
+            ]
+        );
+    }
+}
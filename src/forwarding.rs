@@ -0,0 +1,225 @@
+//! Player info forwarding from proxies to the servers behind them.
+//!
+//! Proxies log players in themselves and connect to the backend servers in
+//! offline mode, forwarding the player's address, uuid and skin along:
+//!
+//! - BungeeCord appends them to `Handshake0::server_address`, separated by
+//!   NUL characters. They aren't signed, so the backend has to be firewalled.
+//! - Velocity's modern forwarding answers a `velocity:player_info` login
+//!   plugin request of the backend, signed with a secret shared between
+//!   proxy and backend. The plugin messages exist since 1.13.
+
+use std::io::Cursor;
+use std::net::IpAddr;
+
+use hmac::{Hmac, Mac};
+use miners_auth::ProfileProperty;
+use miners_encoding::attrs::Var;
+use miners_encoding::{decode, Decode, Encode};
+use sha2::Sha256;
+use uuid::Uuid;
+
+/// The channel of Velocity's login plugin messages.
+pub const VELOCITY_CHANNEL: &str = "velocity:player_info";
+/// The forwarding version containing the address, uuid, name and properties.
+pub const VELOCITY_MODERN_DEFAULT: i32 = 1;
+
+const SIGNATURE_LEN: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ForwardingError {
+    #[error("the proxy forwarded no player info")]
+    NotForwarded,
+    #[error("invalid forwarded address {0:?}")]
+    InvalidAddress(String),
+    #[error("invalid forwarded uuid {0:?}")]
+    InvalidUuid(String),
+    #[error("invalid forwarded properties: {0}")]
+    InvalidProperties(#[from] serde_json::Error),
+    #[error("the forwarded player info isn't signed with the secret")]
+    InvalidSignature,
+    #[error("unsupported forwarding version {0}")]
+    UnsupportedVersion(i32),
+    #[error("malformed forwarded player info: {0}")]
+    Decode(#[from] decode::Error),
+}
+
+fn parse_address(address: &str) -> Result<IpAddr, ForwardingError> {
+    let trimmed = address.trim_start_matches('[').trim_end_matches(']');
+    trimmed
+        .parse()
+        .map_err(|_| ForwardingError::InvalidAddress(address.into()))
+}
+
+/// The player info BungeeCord forwards in the handshake.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BungeeCordInfo {
+    /// The address the player connected to the proxy with.
+    pub host: String,
+    /// The address of the player.
+    pub address: IpAddr,
+    pub uuid: Uuid,
+    pub properties: Vec<ProfileProperty>,
+}
+
+impl BungeeCordInfo {
+    /// Parses the server address of a forwarded handshake.
+    pub fn parse(server_address: &str) -> Result<Self, ForwardingError> {
+        let mut fields = server_address.splitn(4, '\0');
+        let (Some(host), Some(address), Some(uuid)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(ForwardingError::NotForwarded);
+        };
+        let properties = match fields.next() {
+            Some(properties) => serde_json::from_str(properties)?,
+            None => Vec::new(),
+        };
+        Ok(BungeeCordInfo {
+            host: host.into(),
+            address: parse_address(address)?,
+            uuid: Uuid::parse_str(uuid).map_err(|_| ForwardingError::InvalidUuid(uuid.into()))?,
+            properties,
+        })
+    }
+
+    /// The server address to send in the handshake to the backend.
+    pub fn to_server_address(&self) -> String {
+        let mut address = format!("{}\0{}\0{}", self.host, self.address, self.uuid.simple());
+        if !self.properties.is_empty() {
+            let properties =
+                serde_json::to_string(&self.properties).expect("properties serialize to json");
+            address.push('\0');
+            address.push_str(&properties);
+        }
+        address
+    }
+}
+
+/// The player info Velocity forwards in the login plugin response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VelocityInfo {
+    /// The address of the player.
+    pub address: IpAddr,
+    pub uuid: Uuid,
+    pub name: String,
+    pub properties: Vec<ProfileProperty>,
+}
+
+impl VelocityInfo {
+    /// The data of the login plugin request asking for the player info.
+    pub fn request() -> Vec<u8> {
+        vec![VELOCITY_MODERN_DEFAULT as u8]
+    }
+
+    /// Verifies the data of the login plugin response with `secret` and decodes it.
+    pub fn decode(data: &[u8], secret: &[u8]) -> Result<Self, ForwardingError> {
+        if data.len() < SIGNATURE_LEN {
+            return Err(ForwardingError::InvalidSignature);
+        }
+        let (signature, data) = data.split_at(SIGNATURE_LEN);
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any length");
+        mac.update(data);
+        mac.verify_slice(signature)
+            .map_err(|_| ForwardingError::InvalidSignature)?;
+
+        let mut cursor = Cursor::new(data);
+        let version = Var::<i32>::decode(&mut cursor)?.into_inner();
+        if version != VELOCITY_MODERN_DEFAULT {
+            return Err(ForwardingError::UnsupportedVersion(version));
+        }
+        let address = parse_address(<&str>::decode(&mut cursor)?)?;
+        let uuid = Uuid::decode(&mut cursor)?;
+        let name = String::decode(&mut cursor)?;
+        let count = Var::<i32>::decode(&mut cursor)?.into_inner();
+        let mut properties = Vec::new();
+        for _ in 0..count {
+            properties.push(ProfileProperty {
+                name: String::decode(&mut cursor)?,
+                value: String::decode(&mut cursor)?,
+                signature: Option::<String>::decode(&mut cursor)?,
+            });
+        }
+        Ok(VelocityInfo {
+            address,
+            uuid,
+            name,
+            properties,
+        })
+    }
+
+    /// Encodes the data of the login plugin response, signed with `secret`.
+    pub fn encode(&self, secret: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.encode_unsigned(&mut data)
+            .expect("writing to a vec can't fail");
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any length");
+        mac.update(&data);
+        let mut signed = mac.finalize().into_bytes().to_vec();
+        signed.extend_from_slice(&data);
+        signed
+    }
+
+    fn encode_unsigned(&self, data: &mut Vec<u8>) -> miners_encoding::encode::Result<()> {
+        Var::from(VELOCITY_MODERN_DEFAULT).encode(data)?;
+        self.address.to_string().encode(data)?;
+        self.uuid.encode(data)?;
+        self.name.encode(data)?;
+        Var::from(self.properties.len() as i32).encode(data)?;
+        for property in &self.properties {
+            property.name.encode(data)?;
+            property.value.encode(data)?;
+            property.signature.encode(data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties() -> Vec<ProfileProperty> {
+        vec![ProfileProperty {
+            name: "textures".into(),
+            value: "e30=".into(),
+            signature: Some("c2ln".into()),
+        }]
+    }
+
+    #[test]
+    fn bungeecord() {
+        let address = "play.example.com\x00127.0.0.1\x00069a79f444e94726a5befca90e38aaf5\x00[{\"name\":\"textures\",\"value\":\"e30=\",\"signature\":\"c2ln\"}]";
+        let info = BungeeCordInfo::parse(address).unwrap();
+        assert_eq!(info.host, "play.example.com");
+        assert_eq!(info.address, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(
+            info.uuid.to_string(),
+            "069a79f4-44e9-4726-a5be-fca90e38aaf5"
+        );
+        assert_eq!(info.properties, properties());
+        assert_eq!(info.to_server_address(), address);
+
+        assert!(matches!(
+            BungeeCordInfo::parse("play.example.com"),
+            Err(ForwardingError::NotForwarded)
+        ));
+    }
+
+    #[test]
+    fn velocity() {
+        let info = VelocityInfo {
+            address: "::1".parse().unwrap(),
+            uuid: Uuid::from_u128(0x069a79f444e94726a5befca90e38aaf5),
+            name: "Notch".into(),
+            properties: properties(),
+        };
+        let data = info.encode(b"secret");
+        assert_eq!(VelocityInfo::decode(&data, b"secret").unwrap(), info);
+        assert!(matches!(
+            VelocityInfo::decode(&data, b"wrong"),
+            Err(ForwardingError::InvalidSignature)
+        ));
+    }
+}
@@ -6,6 +6,16 @@
 pub use miners_auth as auth;
 #[cfg(feature = "chat")]
 pub use miners_chat as chat;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "forwarding")]
+pub mod forwarding;
+#[cfg(feature = "proxy")]
+pub mod proxy;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(all(feature = "net", feature = "protocol", feature = "version"))]
+pub mod conn;
 #[cfg(feature = "nbt")]
 pub use miners_nbt as nbt;
 #[cfg(feature = "net")]
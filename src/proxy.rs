@@ -0,0 +1,946 @@
+//! Intercepting the packets between clients and servers.
+//!
+//! A `Proxy` forwards the packets between a client connected to it and the
+//! server it connects to on the client's behalf, parsed for the protocol
+//! state and version of the connection. The packets of each direction pass
+//! through a `Hook`, which decides what is forwarded in their place. Packets
+//! which aren't known in the state and version are passed on as `RawPacket`s.
+//!
+//! As the proxy has to read the packets, both sides log in in offline mode.
+//! Compression is negotiated for each side separately, a `SetCompression27`
+//! dropped by the clientbound hook leaves the client side uncompressed.
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+
+use futures_lite::{AsyncRead, AsyncWrite};
+use miners_encoding::attrs::Var;
+use miners_encoding::{decode, encode, Encode};
+use miners_net::conn::{ReadError, ReadHalf, WriteHalf};
+use miners_net::encoding::Encoder;
+use miners_net::proxy_protocol::ProxyHeader;
+use miners_packet::{Packet, RawPacket, State};
+use miners_protocol::netty::handshaking::SbHandshaking;
+use miners_protocol::netty::login::{CbLogin, SbLogin};
+use miners_protocol::netty::play::{CbPlay, SbPlay};
+use miners_protocol::netty::status::{CbStatus, SbStatus};
+use miners_version::ProtocolVersion;
+
+use crate::conn::{Effect, Handshaking, Login, Play, ProtocolState, Status};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Connection(#[from] crate::conn::Error),
+    #[error("the server is in online mode, whose encryption can't be intercepted")]
+    OnlineMode,
+    #[error("unknown protocol version {0}")]
+    UnknownVersion(i32),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Connection(e.into())
+    }
+}
+
+impl From<ReadError> for Error {
+    fn from(e: ReadError) -> Self {
+        Error::Connection(e.into())
+    }
+}
+
+impl From<decode::Error> for Error {
+    fn from(e: decode::Error) -> Self {
+        Error::Connection(e.into())
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(e: encode::Error) -> Self {
+        Error::Connection(e.into())
+    }
+}
+
+/// The state of the proxied connection, passed to the hooks with every packet.
+#[derive(Debug, Clone, Copy)]
+pub struct Context {
+    pub state: State,
+    pub version: ProtocolVersion,
+}
+
+/// A packet sent by the server.
+pub enum Clientbound<'a> {
+    Status(CbStatus<'a>),
+    Login(CbLogin<'a>),
+    Play(CbPlay<'a>),
+    /// A packet which isn't known in the state and version of the connection.
+    Raw(RawPacket<'a>),
+}
+
+/// A packet sent by the client.
+pub enum Serverbound<'a> {
+    Handshaking(SbHandshaking<'a>),
+    Status(SbStatus),
+    Login(SbLogin<'a>),
+    Play(SbPlay<'a>),
+    /// A packet which isn't known in the state and version of the connection.
+    Raw(RawPacket<'a>),
+}
+
+fn encode_raw(packet: &RawPacket<'_>, writer: &mut impl io::Write) -> encode::Result<()> {
+    Var::from(packet.id).encode(writer)?;
+    writer.write_all(packet.data)?;
+    Ok(())
+}
+
+macro_rules! impl_packet {
+    ($name:ident, $($variant:ident),*) => {
+        impl Packet for $name<'_> {
+            fn id_for_version(&self, version: ProtocolVersion) -> Option<i32> {
+                match self {
+                    $(Self::$variant(packet) => packet.id_for_version(version),)*
+                    Self::Raw(packet) => Some(packet.id),
+                }
+            }
+
+            fn encode_for_version(
+                &self,
+                version: ProtocolVersion,
+                writer: &mut impl io::Write,
+            ) -> Option<encode::Result<()>> {
+                match self {
+                    $(Self::$variant(packet) => packet.encode_for_version(version, writer),)*
+                    Self::Raw(packet) => Some(encode_raw(packet, writer)),
+                }
+            }
+        }
+    };
+}
+
+impl_packet!(Clientbound, Status, Login, Play);
+impl_packet!(Serverbound, Handshaking, Status, Login, Play);
+
+/// The packets of one direction.
+trait Intercepted<'a>: Packet + Sized {
+    /// Parses a packet, falling back to `Raw` if it isn't known.
+    fn parse(packet: RawPacket<'a>, cx: Context) -> Self;
+    fn effect(&self) -> Effect;
+}
+
+impl<'a> Intercepted<'a> for Clientbound<'a> {
+    fn parse(packet: RawPacket<'a>, cx: Context) -> Self {
+        let RawPacket { id, data } = packet;
+        let raw = || RawPacket::new(id, data);
+        let parsed = match cx.state {
+            State::Handshaking => return Clientbound::Raw(raw()),
+            State::Status => Status::parse_clientbound(raw(), cx.version).map(Clientbound::Status),
+            State::Login => Login::parse_clientbound(raw(), cx.version).map(Clientbound::Login),
+            State::Play => Play::parse_clientbound(raw(), cx.version).map(Clientbound::Play),
+        };
+        parsed.unwrap_or_else(|_| Clientbound::Raw(raw()))
+    }
+
+    fn effect(&self) -> Effect {
+        match self {
+            Clientbound::Login(packet) => Login::clientbound_effect(packet),
+            _ => Effect::None,
+        }
+    }
+}
+
+impl<'a> Intercepted<'a> for Serverbound<'a> {
+    fn parse(packet: RawPacket<'a>, cx: Context) -> Self {
+        let RawPacket { id, data } = packet;
+        let raw = || RawPacket::new(id, data);
+        let parsed = match cx.state {
+            State::Handshaking => {
+                Handshaking::parse_serverbound(raw(), cx.version).map(Serverbound::Handshaking)
+            }
+            State::Status => Status::parse_serverbound(raw(), cx.version).map(Serverbound::Status),
+            State::Login => Login::parse_serverbound(raw(), cx.version).map(Serverbound::Login),
+            State::Play => Play::parse_serverbound(raw(), cx.version).map(Serverbound::Play),
+        };
+        parsed.unwrap_or_else(|_| Serverbound::Raw(raw()))
+    }
+
+    fn effect(&self) -> Effect {
+        match self {
+            Serverbound::Handshaking(packet) => Handshaking::serverbound_effect(packet),
+            _ => Effect::None,
+        }
+    }
+}
+
+/// What a hook does with a packet.
+pub enum Action<P> {
+    /// Forwards the packet, which may have been modified.
+    Forward(P),
+    /// Forwards nothing.
+    Drop,
+    /// Forwards these packets in order instead, which allows injecting
+    /// packets before or after the original one.
+    Replace(Vec<P>),
+}
+
+pub type HookFuture<'a, P> = Pin<Box<dyn Future<Output = Action<P>> + Send + 'a>>;
+
+/// Intercepts the packets of one direction, `P` is either `Clientbound` or `Serverbound`.
+///
+/// Hooks are implemented for any lifetime of the packet:
+/// `impl<'p> Hook<Serverbound<'p>> for Logger`.
+pub trait Hook<P>: Send {
+    fn intercept<'a>(&'a mut self, cx: Context, packet: P) -> HookFuture<'a, P>
+    where
+        P: 'a;
+}
+
+/// Forwards every packet unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PassThrough;
+
+impl<P: Send> Hook<P> for PassThrough {
+    fn intercept<'a>(&'a mut self, _cx: Context, packet: P) -> HookFuture<'a, P>
+    where
+        P: 'a,
+    {
+        Box::pin(std::future::ready(Action::Forward(packet)))
+    }
+}
+
+/// One side of the proxy, the encoder is used for the packets written to it.
+struct Side<R, W> {
+    read: ReadHalf<R>,
+    write: WriteHalf<W>,
+    encoder: Encoder,
+}
+
+impl<R, W> Side<R, W> {
+    fn new(conn: miners_net::conn::Connection<R, W>) -> Self {
+        Side {
+            read: conn.read_half,
+            write: conn.write_half,
+            encoder: Encoder::new(),
+        }
+    }
+
+    fn enable_compression(&mut self, threshold: i32) {
+        self.read.enable_compression(threshold);
+        self.write.enable_compression(threshold);
+    }
+
+    /// Records the packets after a transition in the state of `cx`.
+    fn capture_state(&self, cx: Context) {
+        for capture in self.read.capture().into_iter().chain(self.write.capture()) {
+            capture.set_state(cx.state);
+            capture.set_version(cx.version);
+        }
+    }
+}
+
+/// The result of forwarding one packet.
+struct Forwarded<T> {
+    /// What the caller made of the packet read, before the hook saw it.
+    inspected: T,
+    /// The effect of the packet read on the side it was read from.
+    effect: Effect,
+    /// The last compression threshold written, which has to be
+    /// applied to the read half of the side written to as well.
+    compression: Option<i32>,
+    written: usize,
+}
+
+/// Reads a packet from `from`, passes it through `hook` and writes what it returns to `to`.
+async fn forward<'r, P, T, R, W>(
+    hook: &mut impl Hook<P>,
+    cx: Context,
+    from: &'r mut ReadHalf<R>,
+    to: &mut WriteHalf<W>,
+    encoder: &mut Encoder,
+    inspect: impl FnOnce(&P) -> Result<T, Error>,
+) -> Result<Forwarded<T>, Error>
+where
+    P: Intercepted<'r>,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let packet = P::parse(from.read_encoded().await?.into_packet()?, cx);
+    let inspected = inspect(&packet)?;
+    let effect = packet.effect();
+    let packets = match hook.intercept(cx, packet).await {
+        Action::Forward(packet) => vec![packet],
+        Action::Drop => Vec::new(),
+        Action::Replace(packets) => packets,
+    };
+    let mut compression = None;
+    for packet in &packets {
+        if packet.id_for_version(cx.version).is_none() {
+            return Err(crate::conn::Error::Unsupported(cx.version).into());
+        }
+        to.write_packet(cx.version, packet, encoder).await?;
+        if let Effect::Compression(threshold) = packet.effect() {
+            // following packets are compressed already
+            to.enable_compression(threshold);
+            compression = Some(threshold);
+        }
+    }
+    to.flush().await?;
+    Ok(Forwarded {
+        inspected,
+        effect,
+        compression,
+        written: packets.len(),
+    })
+}
+
+/// What the proxy makes of a clientbound login packet.
+enum LoginStep {
+    Continue,
+    /// Login plugin requests and unknown packets, which are answered by the client.
+    Request,
+    Disconnected,
+}
+
+fn is_eof(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::Connection(crate::conn::Error::Read(ReadError::Io(e)))
+            if e.kind() == io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Forwards the packets between a client and a server.
+pub struct Proxy<C = PassThrough, S = PassThrough> {
+    /// Intercepts the packets sent by the server.
+    pub clientbound: C,
+    /// Intercepts the packets sent by the client.
+    pub serverbound: S,
+    /// A PROXY protocol header sent to the server before anything else,
+    /// passing the address of the client on to servers expecting one.
+    pub proxy_header: Option<ProxyHeader>,
+}
+
+impl Proxy {
+    /// A proxy forwarding every packet unchanged.
+    pub fn new() -> Self {
+        Self::with_hooks(PassThrough, PassThrough)
+    }
+}
+
+impl Default for Proxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C, S> Proxy<C, S>
+where
+    C: for<'p> Hook<Clientbound<'p>>,
+    S: for<'p> Hook<Serverbound<'p>>,
+{
+    pub fn with_hooks(clientbound: C, serverbound: S) -> Self {
+        Proxy {
+            clientbound,
+            serverbound,
+            proxy_header: None,
+        }
+    }
+
+    /// Sends `header` to the server before the handshake.
+    pub fn with_proxy_header(mut self, header: ProxyHeader) -> Self {
+        self.proxy_header = Some(header);
+        self
+    }
+
+    /// Connects to `upstream`, which is a host with an optional port,
+    /// and forwards the packets between it and `client`.
+    pub async fn connect<R, W>(
+        self,
+        client: miners_net::conn::Connection<R, W>,
+        upstream: &str,
+    ) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let (host, port) = crate::client::split_addr(upstream);
+        let server = crate::client::connect_tcp(host, port).await?;
+        self.run(client, server).await
+    }
+
+    /// Forwards the packets between `client` and `server`, starting with the
+    /// handshake, until either side closes the connection.
+    pub async fn run<CR, CW, SR, SW>(
+        mut self,
+        client: miners_net::conn::Connection<CR, CW>,
+        mut server: miners_net::conn::Connection<SR, SW>,
+    ) -> Result<(), Error>
+    where
+        CR: AsyncRead + Unpin,
+        CW: AsyncWrite + Unpin,
+        SR: AsyncRead + Unpin,
+        SW: AsyncWrite + Unpin,
+    {
+        if let Some(header) = &self.proxy_header {
+            server.write_half.write_proxy_header(header).await?;
+        }
+        let mut client = Side::new(client);
+        let mut server = Side::new(server);
+        let res = match self.handshake(&mut client, &mut server).await {
+            Ok(Some(cx)) => self
+                .play(cx, client, server)
+                .await
+                .map(|never| match never {}),
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        };
+        match res {
+            Err(e) if is_eof(&e) => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Forwards the handshake and the following status or login, returning
+    /// the context for play if the login has succeeded.
+    async fn handshake<CR, CW, SR, SW>(
+        &mut self,
+        client: &mut Side<CR, CW>,
+        server: &mut Side<SR, SW>,
+    ) -> Result<Option<Context>, Error>
+    where
+        CR: AsyncRead + Unpin,
+        CW: AsyncWrite + Unpin,
+        SR: AsyncRead + Unpin,
+        SW: AsyncWrite + Unpin,
+    {
+        let mut cx = Context {
+            state: State::Handshaking,
+            // replaced by the version of the handshake
+            version: ProtocolVersion::new(47).unwrap(),
+        };
+        let handshake = self.serverbound(cx, client, server, |_| Ok(())).await?;
+        let Effect::Handshake { version, next } = handshake.effect else {
+            return Err(decode::Error::InvalidId.into());
+        };
+        cx.version = ProtocolVersion::new(version).map_err(|_| Error::UnknownVersion(version))?;
+        cx.state = next;
+        client.capture_state(cx);
+        server.capture_state(cx);
+
+        if next == State::Status {
+            loop {
+                let request = self.serverbound(cx, client, server, |packet| {
+                    Ok(matches!(packet, Serverbound::Status(SbStatus::Ping0(_))))
+                });
+                let request = request.await?;
+                // the server answers every packet it receives
+                for _ in 0..request.written {
+                    self.clientbound(cx, server, client, |_| Ok(())).await?;
+                }
+                // the ping ends the status, even if the hook dropped it
+                if request.inspected {
+                    return Ok(None);
+                }
+            }
+        }
+
+        self.serverbound(cx, client, server, |_| Ok(())).await?;
+        loop {
+            let response = self.clientbound(cx, server, client, |packet| match packet {
+                Clientbound::Login(CbLogin::EncryptionRequest0(_))
+                | Clientbound::Login(CbLogin::EncryptionRequest19(_)) => Err(Error::OnlineMode),
+                Clientbound::Login(CbLogin::Disconnect0(_)) => Ok(LoginStep::Disconnected),
+                Clientbound::Login(CbLogin::LoginPluginRequest385(_)) | Clientbound::Raw(_) => {
+                    Ok(LoginStep::Request)
+                }
+                _ => Ok(LoginStep::Continue),
+            });
+            let response = response.await?;
+            match response.inspected {
+                LoginStep::Disconnected => return Ok(None),
+                LoginStep::Request => {
+                    // the client answers every request it receives
+                    for _ in 0..response.written {
+                        self.serverbound(cx, client, server, |_| Ok(())).await?;
+                    }
+                }
+                LoginStep::Continue => {}
+            }
+            if let Effect::Finished = response.effect {
+                cx.state = State::Play;
+                client.capture_state(cx);
+                server.capture_state(cx);
+                return Ok(Some(cx));
+            }
+        }
+    }
+
+    /// Forwards a packet sent by the client, applying its effects.
+    async fn serverbound<T, CR, CW, SR, SW>(
+        &mut self,
+        cx: Context,
+        client: &mut Side<CR, CW>,
+        server: &mut Side<SR, SW>,
+        inspect: impl FnOnce(&Serverbound<'_>) -> Result<T, Error>,
+    ) -> Result<Forwarded<T>, Error>
+    where
+        CR: AsyncRead + Unpin,
+        SW: AsyncWrite + Unpin,
+    {
+        let forwarded = forward(
+            &mut self.serverbound,
+            cx,
+            &mut client.read,
+            &mut server.write,
+            &mut server.encoder,
+            inspect,
+        );
+        let forwarded = forwarded.await?;
+        if let Some(threshold) = forwarded.compression {
+            server.read.enable_compression(threshold);
+        }
+        Ok(forwarded)
+    }
+
+    /// Forwards a packet sent by the server, applying its effects.
+    async fn clientbound<T, CR, CW, SR, SW>(
+        &mut self,
+        cx: Context,
+        server: &mut Side<SR, SW>,
+        client: &mut Side<CR, CW>,
+        inspect: impl FnOnce(&Clientbound<'_>) -> Result<T, Error>,
+    ) -> Result<Forwarded<T>, Error>
+    where
+        SR: AsyncRead + Unpin,
+        CW: AsyncWrite + Unpin,
+    {
+        let forwarded = forward(
+            &mut self.clientbound,
+            cx,
+            &mut server.read,
+            &mut client.write,
+            &mut client.encoder,
+            inspect,
+        );
+        let forwarded = forwarded.await?;
+        if let Effect::Compression(threshold) = forwarded.effect {
+            server.enable_compression(threshold);
+        }
+        if let Some(threshold) = forwarded.compression {
+            client.read.enable_compression(threshold);
+        }
+        Ok(forwarded)
+    }
+
+    /// Forwards both directions at once, until either fails.
+    ///
+    /// `SetCompression27` is only applied during the login, in play it
+    /// exists for 1.8 but was never sent by vanilla servers.
+    async fn play<CR, CW, SR, SW>(
+        self,
+        cx: Context,
+        client: Side<CR, CW>,
+        server: Side<SR, SW>,
+    ) -> Result<Infallible, Error>
+    where
+        CR: AsyncRead + Unpin,
+        CW: AsyncWrite + Unpin,
+        SR: AsyncRead + Unpin,
+        SW: AsyncWrite + Unpin,
+    {
+        let Proxy {
+            mut clientbound,
+            mut serverbound,
+            ..
+        } = self;
+        let Side {
+            read: mut client_read,
+            write: mut client_write,
+            encoder: mut client_encoder,
+        } = client;
+        let Side {
+            read: mut server_read,
+            write: mut server_write,
+            encoder: mut server_encoder,
+        } = server;
+        let clientbound = async {
+            loop {
+                forward::<Clientbound<'_>, _, _, _>(
+                    &mut clientbound,
+                    cx,
+                    &mut server_read,
+                    &mut client_write,
+                    &mut client_encoder,
+                    |_| Ok(()),
+                )
+                .await?;
+            }
+        };
+        let serverbound = async {
+            loop {
+                forward::<Serverbound<'_>, _, _, _>(
+                    &mut serverbound,
+                    cx,
+                    &mut client_read,
+                    &mut server_write,
+                    &mut server_encoder,
+                    |_| Ok(()),
+                )
+                .await?;
+            }
+        };
+        futures_lite::future::or(clientbound, serverbound).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{self, Profile};
+    use crate::conn::{ClientConnection, Next, ServerConnection};
+    use futures_lite::future::{block_on, zip};
+    use miners_net::capture::{Capture, CaptureReader};
+    use miners_net::memory::{duplex, SharedBuffer};
+    use miners_net::proxy_protocol::ProxyVersion;
+    use miners_packet::Direction;
+    use miners_protocol::netty::handshaking::serverbound::{Handshake0, NextState0};
+    use miners_protocol::netty::login::clientbound::{
+        LoginPluginRequest385, SetCompression27, Success5,
+    };
+    use miners_protocol::netty::login::serverbound::LoginStart0;
+    use miners_protocol::netty::play::serverbound::ChatMessage0;
+    use miners_protocol::netty::status::clientbound::Response0;
+    use miners_protocol::netty::status::serverbound::{Ping0, Request0};
+
+    /// Renames players and shouts their messages.
+    struct Rename;
+
+    impl<'p> Hook<Serverbound<'p>> for Rename {
+        fn intercept<'a>(
+            &'a mut self,
+            _cx: Context,
+            packet: Serverbound<'p>,
+        ) -> HookFuture<'a, Serverbound<'p>>
+        where
+            Serverbound<'p>: 'a,
+        {
+            Box::pin(async move {
+                match packet {
+                    Serverbound::Login(SbLogin::LoginStart0(mut start)) => {
+                        start.username = "alex".into();
+                        Action::Forward(Serverbound::Login(SbLogin::LoginStart0(start)))
+                    }
+                    Serverbound::Play(SbPlay::ChatMessage0(chat)) => {
+                        let message = chat.message.to_uppercase();
+                        Action::Replace(vec![
+                            Serverbound::Play(SbPlay::ChatMessage0(ChatMessage0 {
+                                message: message.into(),
+                            })),
+                            Serverbound::Play(SbPlay::ChatMessage0(ChatMessage0 {
+                                message: "!".into(),
+                            })),
+                        ])
+                    }
+                    packet => Action::Forward(packet),
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn intercept() {
+        let version = ProtocolVersion::new(47).unwrap();
+        let (client, mut proxy_client) = duplex(1024);
+        let (proxy_server, server) = duplex(1024);
+        let file = SharedBuffer::default();
+        let capture = Capture::new(file.clone(), State::Handshaking, version).unwrap();
+        proxy_client.set_capture(capture.clone(), Direction::Serverbound);
+        let client = async {
+            let http = reqwest::Client::new();
+            let profile = Profile::offline("steve");
+            let mut conn = client::login(client, "localhost", 25565, version, &profile, &http)
+                .await
+                .unwrap();
+            let chat = SbPlay::ChatMessage0(ChatMessage0 {
+                message: "hello".into(),
+            });
+            conn.write(chat).await.unwrap();
+            conn.flush().await.unwrap();
+        };
+        let header = ProxyHeader::new(
+            ProxyVersion::V2,
+            "203.0.113.7:56324".parse().unwrap(),
+            "10.0.0.2:25565".parse().unwrap(),
+        );
+        let server = async {
+            let mut server = server;
+            let received = server.read_half.read_proxy_header().await.unwrap();
+            assert_eq!(received, &header);
+            let mut server = ServerConnection::new(server, version);
+            server.read().await.unwrap();
+            let Ok(Next::Login(mut server)) = server.next() else {
+                panic!("didn't move on to login")
+            };
+            let name = match server.read().await.unwrap() {
+                SbLogin::LoginStart0(start) => start.username.into_owned(),
+                _ => panic!("expected LoginStart"),
+            };
+            assert_eq!(name, "alex");
+            let compression = CbLogin::SetCompression27(SetCompression27 { threshold: 2 });
+            server.write(compression).await.unwrap();
+            let success = CbLogin::Success5(Success5 {
+                uuid: None,
+                username: name.into(),
+            });
+            server.write(success).await.unwrap();
+            server.flush().await.unwrap();
+            let Ok(mut server) = server.into_play() else {
+                panic!("didn't move on to play")
+            };
+            let mut messages = Vec::new();
+            for _ in 0..2 {
+                match server.read().await.unwrap() {
+                    SbPlay::ChatMessage0(chat) => messages.push(chat.message.into_owned()),
+                    _ => panic!("expected a chat message"),
+                }
+            }
+            assert_eq!(messages, ["HELLO", "!"]);
+        };
+        let proxy = Proxy::with_hooks(PassThrough, Rename)
+            .with_proxy_header(header.clone())
+            .run(proxy_client, proxy_server);
+        let ((_, _), res) = block_on(zip(zip(client, server), proxy));
+        res.unwrap();
+
+        capture.flush().unwrap();
+        let states = CaptureReader::new(&file.contents()[..])
+            .unwrap()
+            .map(|record| record.unwrap().state)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            [
+                State::Handshaking,
+                State::Login,
+                State::Login,
+                State::Login,
+                State::Play,
+            ]
+        );
+    }
+
+    /// Sends every status request twice and drops the ping.
+    struct Doubling;
+
+    impl<'p> Hook<Serverbound<'p>> for Doubling {
+        fn intercept<'a>(
+            &'a mut self,
+            _cx: Context,
+            packet: Serverbound<'p>,
+        ) -> HookFuture<'a, Serverbound<'p>>
+        where
+            Serverbound<'p>: 'a,
+        {
+            Box::pin(async move {
+                match packet {
+                    Serverbound::Status(SbStatus::Request0(_)) => Action::Replace(vec![
+                        Serverbound::Status(SbStatus::Request0(Request0 {})),
+                        Serverbound::Status(SbStatus::Request0(Request0 {})),
+                    ]),
+                    Serverbound::Status(SbStatus::Ping0(_)) => Action::Drop,
+                    packet => Action::Forward(packet),
+                }
+            })
+        }
+    }
+
+    fn handshake(next_state: NextState0) -> SbHandshaking<'static> {
+        SbHandshaking::Handshake0(Handshake0 {
+            protocol_version: 47,
+            server_address: "localhost".into(),
+            server_port: 25565,
+            next_state,
+        })
+    }
+
+    #[test]
+    fn status_hooks() {
+        let version = ProtocolVersion::new(47).unwrap();
+        let (client, proxy_client) = duplex(1024);
+        let (proxy_server, server) = duplex(1024);
+        let client = async {
+            let mut client = ClientConnection::new(client, version);
+            client.write(handshake(NextState0::Status)).await.unwrap();
+            let Ok(Next::Status(mut client)) = client.next() else {
+                panic!("didn't move on to status")
+            };
+            client.write(SbStatus::Request0(Request0 {})).await.unwrap();
+            client.flush().await.unwrap();
+            for _ in 0..2 {
+                let response = client.read().await.unwrap();
+                assert!(matches!(response, CbStatus::Response0(_)));
+            }
+            client
+                .write(SbStatus::Ping0(Ping0 { time: 1 }))
+                .await
+                .unwrap();
+            client.flush().await.unwrap();
+            // the proxy closes the connection instead of waiting for another ping
+            assert!(client.read().await.is_err());
+        };
+        let server = async {
+            let mut server = ServerConnection::new(server, version);
+            server.read().await.unwrap();
+            let Ok(Next::Status(mut server)) = server.next() else {
+                panic!("didn't move on to status")
+            };
+            for _ in 0..2 {
+                let request = server.read().await.unwrap();
+                assert!(matches!(request, SbStatus::Request0(_)));
+                let response = Response0 { data: "{}".into() };
+                server.write(CbStatus::Response0(response)).await.unwrap();
+                server.flush().await.unwrap();
+            }
+        };
+        let proxy = Proxy::with_hooks(PassThrough, Doubling).run(proxy_client, proxy_server);
+        let ((_, _), res) = block_on(zip(zip(client, server), proxy));
+        res.unwrap();
+    }
+
+    #[test]
+    fn unknown_login_packet() {
+        let version = ProtocolVersion::new(47).unwrap();
+        let (client, proxy_client) = duplex(1024);
+        let (proxy_server, server) = duplex(1024);
+        let client = async {
+            let mut client = ClientConnection::new(client, version);
+            client.write(handshake(NextState0::Login)).await.unwrap();
+            let Ok(Next::Login(mut client)) = client.next() else {
+                panic!("didn't move on to login")
+            };
+            let start = LoginStart0 {
+                username: "steve".into(),
+            };
+            client.write(SbLogin::LoginStart0(start)).await.unwrap();
+            client.flush().await.unwrap();
+
+            let inner = client.inner_mut();
+            let request = inner.read_half.read_encoded().await.unwrap();
+            let request = request.into_packet().unwrap();
+            assert_eq!((request.id, request.data), (0x04, &b"request"[..]));
+            let answer = Serverbound::Raw(RawPacket::new(0x02, b"answer"));
+            let mut encoder = Encoder::new();
+            let write_half = &mut inner.write_half;
+            write_half
+                .write_packet(version, &answer, &mut encoder)
+                .await
+                .unwrap();
+            write_half.flush().await.unwrap();
+
+            let success = client.read().await.unwrap();
+            assert!(matches!(success, CbLogin::Success5(_)));
+        };
+        let server = async {
+            let mut server = ServerConnection::new(server, version);
+            server.read().await.unwrap();
+            let Ok(Next::Login(mut server)) = server.next() else {
+                panic!("didn't move on to login")
+            };
+            server.read().await.unwrap();
+
+            let inner = server.inner_mut();
+            let request = Clientbound::Raw(RawPacket::new(0x04, b"request"));
+            let mut encoder = Encoder::new();
+            let write_half = &mut inner.write_half;
+            write_half
+                .write_packet(version, &request, &mut encoder)
+                .await
+                .unwrap();
+            write_half.flush().await.unwrap();
+            let answer = inner.read_half.read_encoded().await.unwrap();
+            let answer = answer.into_packet().unwrap();
+            assert_eq!((answer.id, answer.data), (0x02, &b"answer"[..]));
+
+            let success = CbLogin::Success5(Success5 {
+                uuid: None,
+                username: "steve".into(),
+            });
+            server.write(success).await.unwrap();
+            server.flush().await.unwrap();
+        };
+        let proxy = Proxy::new().run(proxy_client, proxy_server);
+        let ((_, _), res) = block_on(zip(zip(client, server), proxy));
+        res.unwrap();
+    }
+
+    /// Drops the login plugin requests of the server.
+    struct DropRequests;
+
+    impl<'p> Hook<Clientbound<'p>> for DropRequests {
+        fn intercept<'a>(
+            &'a mut self,
+            _cx: Context,
+            packet: Clientbound<'p>,
+        ) -> HookFuture<'a, Clientbound<'p>>
+        where
+            Clientbound<'p>: 'a,
+        {
+            Box::pin(async move {
+                match packet {
+                    Clientbound::Login(CbLogin::LoginPluginRequest385(_)) => Action::Drop,
+                    packet => Action::Forward(packet),
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn dropped_login_request() {
+        let version = ProtocolVersion::new(404).unwrap();
+        let (client, proxy_client) = duplex(1024);
+        let (proxy_server, server) = duplex(1024);
+        let client = async {
+            let mut client = ClientConnection::new(client, version);
+            let SbHandshaking::Handshake0(mut handshake) = handshake(NextState0::Login);
+            handshake.protocol_version = 404;
+            let handshake = SbHandshaking::Handshake0(handshake);
+            client.write(handshake).await.unwrap();
+            let Ok(Next::Login(mut client)) = client.next() else {
+                panic!("didn't move on to login")
+            };
+            let start = LoginStart0 {
+                username: "steve".into(),
+            };
+            client.write(SbLogin::LoginStart0(start)).await.unwrap();
+            client.flush().await.unwrap();
+            // the request never arrives, so there is nothing to answer
+            let success = client.read().await.unwrap();
+            assert!(matches!(success, CbLogin::Success5(_)));
+        };
+        let server = async {
+            let mut server = ServerConnection::new(server, version);
+            server.read().await.unwrap();
+            let Ok(Next::Login(mut server)) = server.next() else {
+                panic!("didn't move on to login")
+            };
+            server.read().await.unwrap();
+            let request = CbLogin::LoginPluginRequest385(LoginPluginRequest385 {
+                message_id: 0,
+                channel: "velocity:player_info".into(),
+                data: b"request"[..].into(),
+            });
+            server.write(request).await.unwrap();
+            let success = CbLogin::Success5(Success5 {
+                uuid: None,
+                username: "steve".into(),
+            });
+            server.write(success).await.unwrap();
+            server.flush().await.unwrap();
+        };
+        let proxy = Proxy::with_hooks(DropRequests, PassThrough).run(proxy_client, proxy_server);
+        let ((_, _), res) = block_on(zip(zip(client, server), proxy));
+        res.unwrap();
+    }
+}
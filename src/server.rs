@@ -0,0 +1,1052 @@
+//! Accepting players.
+//!
+//! An `Acceptor` takes freshly accepted connections through the handshake,
+//! answers status requests, including the legacy ping of clients from before
+//! 1.7, and performs the login, returning the players
+//! which have logged in. `Listener` runs it for every connection to a TCP
+//! socket, yielding logged in players as they arrive.
+
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_lite::io::{BufReader, BufWriter};
+use futures_lite::{AsyncRead, AsyncWrite};
+use miners_auth::{GameProfile, HttpClient, ProfileProperty};
+use miners_net::crypto::{self, CryptoError, KeyPair};
+use miners_net::legacy::LegacyStatus;
+use miners_packet::{Packet, State};
+use miners_protocol::netty::handshaking::SbHandshaking;
+use miners_protocol::netty::login::clientbound::LoginPluginRequest385;
+use miners_protocol::netty::login::clientbound::{
+    Disconnect0, EncryptionRequest0, EncryptionRequest19, SetCompression27, Success0, Success5,
+};
+use miners_protocol::netty::login::serverbound::LoginStart0;
+use miners_protocol::netty::login::{CbLogin, SbLogin};
+use miners_protocol::netty::status::clientbound::{Ping0, Response0};
+use miners_protocol::netty::status::{
+    CbStatus, SbStatus, ServerStatus, StatusPlayers, StatusVersion,
+};
+use miners_version::ProtocolVersion;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio_util::compat::Compat;
+use uuid::Uuid;
+
+use crate::conn::{Handshaking, Login, Next, Play, ServerConnection, Status};
+use crate::forwarding::{BungeeCordInfo, ForwardingError, VelocityInfo, VELOCITY_CHANNEL};
+
+/// Builds the status response for a client of the given version.
+pub type StatusHandler = Arc<dyn Fn(ProtocolVersion) -> ServerStatus + Send + Sync>;
+
+/// The configuration of an `Acceptor`.
+#[derive(Clone)]
+pub struct ServerConfig {
+    /// Whether players are authenticated with the session server,
+    /// which also enables encryption.
+    pub online_mode: bool,
+    /// Packets with a length of at least `compression_threshold` will be
+    /// compressed, a negative threshold disables compression.
+    pub compression_threshold: i32,
+    pub status: StatusHandler,
+    /// How players are forwarded by a proxy, which has authenticated them
+    /// already. Connections without forwarded player info are refused.
+    pub forwarding: Forwarding,
+    /// Whether connections start with a PROXY protocol header, as sent by load
+    /// balancers like HAProxy. Connections without one are refused.
+    pub proxy_protocol: bool,
+    pub timeouts: Timeouts,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            online_mode: true,
+            compression_threshold: 256,
+            status: Arc::new(|version| ServerStatus {
+                version: StatusVersion {
+                    name: "miners".into(),
+                    protocol: *version,
+                },
+                players: Some(StatusPlayers::default()),
+                ..Default::default()
+            }),
+            forwarding: Forwarding::None,
+            proxy_protocol: false,
+            timeouts: Timeouts::default(),
+        }
+    }
+}
+
+/// How long clients may take for each stage of being accepted.
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    /// Sending the PROXY protocol header and the handshake or legacy ping.
+    pub handshake: Duration,
+    /// Requesting the status and pinging.
+    pub status: Duration,
+    /// Logging in, including the session verification.
+    pub login: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: Duration::from_secs(5),
+            status: Duration::from_secs(5),
+            login: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The player info forwarding of the proxy in front of the server.
+#[derive(Clone, Debug, Default)]
+pub enum Forwarding {
+    #[default]
+    None,
+    /// BungeeCord's unsigned forwarding in the handshake.
+    BungeeCord,
+    /// Velocity's modern forwarding signed with the secret, which
+    /// requires clients from 1.13 on.
+    Velocity(Vec<u8>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Connection(#[from] crate::conn::Error),
+    #[error("the client sent a packet of the wrong state")]
+    UnexpectedPacket,
+    #[error("protocol version {0} can't log in")]
+    UnsupportedVersion(i32),
+    #[error("invalid username {0:?}")]
+    InvalidName(String),
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error("the verify token doesn't match")]
+    VerifyToken,
+    #[error("verifying the session failed: {0}")]
+    Auth(#[from] miners_auth::Error),
+    #[error("{0} hasn't joined the server")]
+    NotJoined(String),
+    #[error(transparent)]
+    Forwarding(#[from] ForwardingError),
+    #[error("the client timed out in the {0:?} state")]
+    TimedOut(State),
+}
+
+/// A player which has logged in.
+pub struct Player<R, W> {
+    /// The connection, whose version is the one of the client.
+    pub connection: ServerConnection<Play, R, W>,
+    pub uuid: Uuid,
+    pub name: String,
+    /// The properties of the profile, like the skin, empty in offline mode.
+    pub properties: Vec<ProfileProperty>,
+    /// The address of the player forwarded by a proxy, whose
+    /// address the connection has been accepted from then.
+    pub forwarded_address: Option<IpAddr>,
+    /// The address of the player sent in the PROXY protocol header,
+    /// `None` if it is disabled or the balancer didn't know it.
+    pub source_address: Option<SocketAddr>,
+}
+
+/// The uuid of a player in offline mode, derived from the name.
+pub fn offline_uuid(name: &str) -> Uuid {
+    let digest = md5::compute(format!("OfflinePlayer:{name}"));
+    uuid::Builder::from_md5_bytes(digest.0).into_uuid()
+}
+
+/// A connection which has sent the handshake.
+struct Handshake<R, W> {
+    conn: ServerConnection<Handshaking, R, W>,
+    /// The version sent in the handshake, which may be unknown.
+    version: i32,
+    bungeecord: Option<Result<BungeeCordInfo, ForwardingError>>,
+    source_address: Option<SocketAddr>,
+}
+
+/// Takes connections through the handshake and login.
+pub struct Acceptor<H = reqwest::Client> {
+    config: ServerConfig,
+    key_pair: Option<KeyPair>,
+    http: H,
+}
+
+impl Acceptor {
+    pub fn new(config: ServerConfig) -> Result<Self, CryptoError> {
+        Self::with_http(config, reqwest::Client::new())
+    }
+}
+
+impl<H: HttpClient> Acceptor<H> {
+    /// Uses `http` to verify sessions with the session server.
+    pub fn with_http(config: ServerConfig, http: H) -> Result<Self, CryptoError> {
+        let key_pair = match config.online_mode {
+            true => Some(KeyPair::generate()?),
+            false => None,
+        };
+        Ok(Acceptor {
+            config,
+            key_pair,
+            http,
+        })
+    }
+
+    /// Reads the handshake and answers the status request or logs the player
+    /// in, returning `None` once a status request has been answered.
+    pub async fn accept<R, W>(
+        &self,
+        conn: miners_net::conn::Connection<R, W>,
+    ) -> Result<Option<Player<R, W>>, Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let timeouts = &self.config.timeouts;
+        let handshake = self.handshake(conn);
+        let handshake = timeout(State::Handshaking, timeouts.handshake, handshake).await?;
+        let Some(Handshake {
+            conn,
+            version,
+            bungeecord,
+            source_address,
+        }) = handshake
+        else {
+            return Ok(None);
+        };
+        match conn.next() {
+            Ok(Next::Status(conn)) => {
+                timeout(State::Status, timeouts.status, self.status(conn)).await?;
+                Ok(None)
+            }
+            Ok(Next::Login(conn)) => {
+                let login = self.login(conn, version, bungeecord, source_address);
+                timeout(State::Login, timeouts.login, login).await.map(Some)
+            }
+            Err(_) => Err(Error::UnexpectedPacket),
+        }
+    }
+
+    /// Reads the handshake, returning `None` once a legacy ping has been answered.
+    async fn handshake<R, W>(
+        &self,
+        mut conn: miners_net::conn::Connection<R, W>,
+    ) -> Result<Option<Handshake<R, W>>, Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if self.config.proxy_protocol {
+            let header = conn.read_half.read_proxy_header().await;
+            header.map_err(crate::conn::Error::from)?;
+        }
+        let source_address = conn.source_addr();
+        let ping = conn.read_half.read_legacy_ping().await;
+        if let Some(ping) = ping.map_err(crate::conn::Error::from)? {
+            // legacy clients predate netty, so they get the status of the oldest version
+            let status = (self.config.status)(ProtocolVersion::new(0).unwrap());
+            let status = legacy_status(&status);
+            conn.write_half.write_legacy_status(&status, &ping).await?;
+            conn.write_half.flush().await?;
+            return Ok(None);
+        }
+        // the version is replaced by the one of the handshake
+        let mut conn = ServerConnection::new(conn, ProtocolVersion::new(47).unwrap());
+        let SbHandshaking::Handshake0(handshake) = conn.read().await?;
+        let version = handshake.protocol_version;
+        let bungeecord = match self.config.forwarding {
+            Forwarding::BungeeCord => Some(BungeeCordInfo::parse(&handshake.server_address)),
+            _ => None,
+        };
+        Ok(Some(Handshake {
+            conn,
+            version,
+            bungeecord,
+            source_address,
+        }))
+    }
+
+    /// Answers the status request and the ping, like vanilla only one
+    /// status request is answered.
+    async fn status<R, W>(&self, mut conn: ServerConnection<Status, R, W>) -> Result<(), Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut requested = false;
+        loop {
+            match conn.read().await {
+                Ok(SbStatus::Request0(_)) if !requested => requested = true,
+                Ok(SbStatus::Request0(_)) => return Err(Error::UnexpectedPacket),
+                Ok(SbStatus::Ping0(ping)) => {
+                    let time = ping.time;
+                    conn.write(CbStatus::Ping0(Ping0 { time })).await?;
+                    conn.flush().await?;
+                    return Ok(());
+                }
+                // clients may close the connection without pinging
+                Err(crate::conn::Error::Read(miners_net::codec::ReadError::Io(e)))
+                    if e.kind() == io::ErrorKind::UnexpectedEof =>
+                {
+                    return Ok(())
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let status = (self.config.status)(conn.version());
+            conn.write(CbStatus::Response0(Response0::from_status(&status)))
+                .await?;
+            conn.flush().await?;
+        }
+    }
+
+    async fn login<R, W>(
+        &self,
+        mut conn: ServerConnection<Login, R, W>,
+        version: i32,
+        bungeecord: Option<Result<BungeeCordInfo, ForwardingError>>,
+        source_address: Option<SocketAddr>,
+    ) -> Result<Player<R, W>, Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if !ProtocolVersion::new(version).is_ok_and(can_log_in) {
+            match disconnect(&mut conn, "Outdated server").await {
+                // clients whose version has no disconnect only see the connection close
+                Ok(()) | Err(Error::Connection(crate::conn::Error::Unsupported(_))) => {}
+                Err(e) => return Err(e),
+            }
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let name = match conn.read().await? {
+            SbLogin::LoginStart0(start) => start.username.into_owned(),
+            _ => return Err(Error::UnexpectedPacket),
+        };
+        if name.is_empty()
+            || name.len() > 16
+            || !name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        {
+            disconnect(&mut conn, "Invalid username").await?;
+            return Err(Error::InvalidName(name));
+        }
+
+        let forwarded = match (&self.config.forwarding, bungeecord) {
+            (_, Some(info)) => Some(info.map(|info| VelocityInfo {
+                address: info.address,
+                uuid: info.uuid,
+                name: name.clone(),
+                properties: info.properties,
+            })),
+            (Forwarding::Velocity(secret), None) => Some(velocity(&mut conn, secret).await?),
+            _ => None,
+        };
+        let forwarded = match forwarded {
+            Some(Ok(info)) => Some(info),
+            Some(Err(e)) => {
+                disconnect(
+                    &mut conn,
+                    "IP forwarding is enabled, but the proxy forwarded nothing",
+                )
+                .await?;
+                return Err(e.into());
+            }
+            None => None,
+        };
+        let forwarded_address = forwarded.as_ref().map(|info| info.address);
+
+        let profile = match (forwarded, &self.key_pair) {
+            (Some(info), _) => (info.uuid, info.name, info.properties),
+            (None, Some(key_pair)) => {
+                let profile = self.authenticate(&mut conn, key_pair, &name).await?;
+                let uuid = Uuid::parse_str(&profile.id).ok();
+                match uuid {
+                    Some(uuid) => (uuid, profile.name, profile.properties),
+                    None => {
+                        disconnect(&mut conn, "Failed to verify username!").await?;
+                        return Err(Error::NotJoined(name));
+                    }
+                }
+            }
+            (None, None) => (offline_uuid(&name), name, Vec::new()),
+        };
+        let (uuid, name, properties) = profile;
+
+        let version = *conn.version();
+        if self.config.compression_threshold >= 0 && version >= 27 {
+            let threshold = self.config.compression_threshold;
+            conn.write(CbLogin::SetCompression27(SetCompression27 { threshold }))
+                .await?;
+        }
+        conn.write(success(conn.version(), uuid, &name)).await?;
+        conn.flush().await?;
+        let Ok(connection) = conn.into_play() else {
+            unreachable!("the login has succeeded")
+        };
+        Ok(Player {
+            connection,
+            uuid,
+            name,
+            properties,
+            forwarded_address,
+            source_address,
+        })
+    }
+
+    /// Performs the encryption handshake and verifies the session.
+    async fn authenticate<R, W>(
+        &self,
+        conn: &mut ServerConnection<Login, R, W>,
+        key_pair: &KeyPair,
+        name: &str,
+    ) -> Result<GameProfile, Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let public_key = key_pair.public_key_der();
+        let verify_token = crypto::generate_verify_token();
+        let request = if *conn.version() < 19 {
+            CbLogin::EncryptionRequest0(EncryptionRequest0 {
+                server_id: "".into(),
+                public_key: public_key.into(),
+                verify_token: verify_token[..].into(),
+            })
+        } else {
+            CbLogin::EncryptionRequest19(EncryptionRequest19 {
+                server_id: "".into(),
+                public_key: public_key.into(),
+                verify_token: verify_token[..].into(),
+            })
+        };
+        conn.write(request).await?;
+        conn.flush().await?;
+
+        let (shared_secret, token) = match conn.read().await? {
+            SbLogin::EncryptionResponse0(response) => (
+                key_pair.decrypt_shared_secret(&response.secret)?,
+                key_pair.decrypt(&response.verify_token)?,
+            ),
+            SbLogin::EncryptionResponse19(response) => (
+                key_pair.decrypt_shared_secret(&response.secret)?,
+                key_pair.decrypt(&response.verify_token)?,
+            ),
+            _ => return Err(Error::UnexpectedPacket),
+        };
+        if token != verify_token {
+            return Err(Error::VerifyToken);
+        }
+        conn.enable_encryption(&shared_secret)?;
+
+        let hash = crypto::server_hash("", &shared_secret, public_key);
+        match miners_auth::has_joined(name, &hash, &self.http).await? {
+            Some(profile) => Ok(profile),
+            None => {
+                disconnect(conn, "Failed to verify username!").await?;
+                Err(Error::NotJoined(name.into()))
+            }
+        }
+    }
+}
+
+/// Asks Velocity for the player info with a login plugin request.
+async fn velocity<R, W>(
+    conn: &mut ServerConnection<Login, R, W>,
+    secret: &[u8],
+) -> Result<Result<VelocityInfo, ForwardingError>, Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // the login plugin messages were added in 1.13
+    if *conn.version() < 385 {
+        return Ok(Err(ForwardingError::NotForwarded));
+    }
+    let message_id = 0;
+    conn.write(CbLogin::LoginPluginRequest385(LoginPluginRequest385 {
+        message_id,
+        channel: VELOCITY_CHANNEL.into(),
+        data: VelocityInfo::request().into(),
+    }))
+    .await?;
+    conn.flush().await?;
+    match conn.read().await? {
+        SbLogin::LoginPluginResponse385(response) if response.message_id == message_id => {
+            Ok(match response.data {
+                Some(data) => VelocityInfo::decode(&data, secret),
+                // the client isn't behind velocity
+                None => Err(ForwardingError::NotForwarded),
+            })
+        }
+        _ => Err(Error::UnexpectedPacket),
+    }
+}
+
+/// Whether the packets of the login exist in `version`.
+fn can_log_in(version: ProtocolVersion) -> bool {
+    let start = SbLogin::LoginStart0(LoginStart0 {
+        username: "".into(),
+    });
+    let success = success(version, Uuid::nil(), "");
+    start.id_for_version(version).is_some() && success.id_for_version(version).is_some()
+}
+
+fn success(version: ProtocolVersion, uuid: Uuid, name: &str) -> CbLogin<'_> {
+    match *version {
+        5 | 14.. => CbLogin::Success5(Success5 {
+            uuid: Some(uuid),
+            username: name.into(),
+        }),
+        _ => CbLogin::Success0(Success0 {
+            uuid: uuid.into(),
+            username: name.into(),
+        }),
+    }
+}
+
+/// Fails with `Error::TimedOut` if `stage` takes longer than `duration`.
+async fn timeout<T>(
+    state: State,
+    duration: Duration,
+    stage: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match tokio::time::timeout(duration, stage).await {
+        Ok(res) => res,
+        Err(_) => Err(Error::TimedOut(state)),
+    }
+}
+
+fn legacy_status(status: &ServerStatus) -> LegacyStatus {
+    let players = status.players.clone().unwrap_or_default();
+    LegacyStatus {
+        // like vanilla, a protocol no client has, so the version name is shown
+        protocol: 127,
+        version: status.version.name.clone(),
+        motd: status.description.to_plain(),
+        online: players.online,
+        max: players.max,
+    }
+}
+
+async fn disconnect<R, W>(
+    conn: &mut ServerConnection<Login, R, W>,
+    reason: &str,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let reason = serde_json::json!({ "text": reason });
+    conn.write(CbLogin::Disconnect0(Disconnect0 {
+        reason: reason.to_string().into(),
+    }))
+    .await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+pub type TcpPlayer = Player<BufReader<Compat<OwnedReadHalf>>, BufWriter<Compat<OwnedWriteHalf>>>;
+
+/// Accepts TCP connections and logs them in, each on its own task.
+///
+/// Connections failing to log in are dropped. Players are returned along
+/// with their address, which is the one of the PROXY protocol header if present.
+pub struct Listener {
+    players: mpsc::Receiver<(TcpPlayer, SocketAddr)>,
+    local_addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Listener {
+    /// Binds to `addr`, must be called within a tokio runtime.
+    pub async fn bind<H>(addr: impl ToSocketAddrs, acceptor: Acceptor<H>) -> io::Result<Self>
+    where
+        H: HttpClient + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (sender, players) = mpsc::channel(64);
+        let acceptor = Arc::new(acceptor);
+        let task = tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    if stream.set_nodelay(true).is_err() {
+                        return;
+                    }
+                    let conn = miners_net::conn::Connection::from_tcp_stream(stream);
+                    if let Ok(Some(player)) = acceptor.accept(conn).await {
+                        let addr = player.source_address.unwrap_or(addr);
+                        let _ = sender.send((player, addr)).await;
+                    }
+                });
+            }
+        });
+        Ok(Listener {
+            players,
+            local_addr,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Waits for the next player to log in.
+    pub async fn next(&mut self) -> Option<(TcpPlayer, SocketAddr)> {
+        self.players.recv().await
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{self, Profile};
+    use crate::conn::ClientConnection;
+    use futures_lite::future::zip;
+    use miners_auth::{Auth, SessionError};
+    use miners_net::memory::duplex;
+    use miners_net::proxy_protocol::{ProxyHeader, ProxyVersion};
+    use miners_protocol::netty::handshaking::serverbound::{Handshake0, NextState0};
+    use miners_protocol::netty::handshaking::SbHandshaking;
+    use miners_protocol::netty::login::serverbound::{LoginPluginResponse385, LoginStart0};
+    use miners_protocol::netty::status::serverbound::Request0;
+    use std::sync::Mutex;
+
+    fn offline() -> ServerConfig {
+        ServerConfig {
+            online_mode: false,
+            ..Default::default()
+        }
+    }
+
+    /// A session server remembering the hash of the last join.
+    #[derive(Clone, Default)]
+    struct MockSession(Arc<Mutex<Option<String>>>);
+
+    #[async_trait::async_trait]
+    impl HttpClient for MockSession {
+        type Body = Vec<u8>;
+
+        async fn execute_request(
+            &self,
+            req: http::Request<Vec<u8>>,
+        ) -> anyhow::Result<http::Response<Vec<u8>>> {
+            let uri = req.uri().to_string();
+            let (status, body) = if uri.ends_with("/join") {
+                let join: serde_json::Value = serde_json::from_slice(req.body())?;
+                if join["accessToken"] == "token" {
+                    *self.0.lock().unwrap() = join["serverId"].as_str().map(Into::into);
+                    (204, Vec::new())
+                } else {
+                    let error = SessionError {
+                        error: "ForbiddenOperationException".into(),
+                        error_message: "Invalid token.".into(),
+                    };
+                    (403, serde_json::to_vec(&error)?)
+                }
+            } else {
+                match &*self.0.lock().unwrap() {
+                    Some(hash) if uri.contains(&format!("serverId={hash}")) => {
+                        let profile = GameProfile {
+                            id: "069a79f444e94726a5befca90e38aaf5".into(),
+                            name: "steve".into(),
+                            properties: vec![ProfileProperty {
+                                name: "textures".into(),
+                                value: "e30=".into(),
+                                signature: Some("c2ln".into()),
+                            }],
+                        };
+                        (200, serde_json::to_vec(&profile)?)
+                    }
+                    _ => (204, Vec::new()),
+                }
+            };
+            Ok(http::Response::builder().status(status).body(body)?)
+        }
+    }
+
+    #[tokio::test]
+    async fn localhost_login() {
+        let acceptor = Acceptor::new(offline()).unwrap();
+        let mut listener = Listener::bind("127.0.0.1:0", acceptor).await.unwrap();
+        let addr = listener.local_addr().to_string();
+        let version = ProtocolVersion::new(47).unwrap();
+        let client = tokio::spawn(async move {
+            client::connect(&addr, version, Profile::offline("steve"))
+                .await
+                .unwrap()
+        });
+        let (player, _) = listener.next().await.unwrap();
+        assert_eq!(player.name, "steve");
+        assert_eq!(player.uuid, offline_uuid("steve"));
+        assert_eq!(*player.connection.version(), 47);
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn status() {
+        let acceptor = Acceptor::new(offline()).unwrap();
+        let (client, server) = duplex(1024);
+        let version = ProtocolVersion::new(47).unwrap();
+        let client = client::ping(client, "localhost", 25565, version);
+        let (ping, accepted) = zip(client, acceptor.accept(server)).await;
+        assert_eq!(ping.unwrap().status.version.protocol, 47);
+        assert!(accepted.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn legacy_status() {
+        let acceptor = Acceptor::new(offline()).unwrap();
+        let (client, server) = duplex(1024);
+        let client = client::ping_legacy(client, "localhost", 25565);
+        let (status, accepted) = zip(client, acceptor.accept(server)).await;
+        let status = status.unwrap();
+        assert_eq!((status.protocol, status.version.as_str()), (127, "miners"));
+        assert!(accepted.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn bungeecord() {
+        let acceptor = Acceptor::new(ServerConfig {
+            forwarding: Forwarding::BungeeCord,
+            ..offline()
+        })
+        .unwrap();
+        let (client, server) = duplex(1024);
+        let version = ProtocolVersion::new(47).unwrap();
+        let info = BungeeCordInfo {
+            host: "localhost".into(),
+            address: IpAddr::from([10, 0, 0, 1]),
+            uuid: Uuid::from_u128(1),
+            properties: Vec::new(),
+        };
+        let host = info.to_server_address();
+        let client = async {
+            let http = reqwest::Client::new();
+            let profile = Profile::offline("steve");
+            client::login(client, &host, 25565, version, &profile, &http)
+                .await
+                .unwrap()
+        };
+        let (_, player) = zip(client, acceptor.accept(server)).await;
+        let player = player.unwrap().unwrap();
+        assert_eq!(player.uuid, info.uuid);
+        assert_eq!(player.forwarded_address, Some(info.address));
+    }
+
+    /// Logs in like Velocity, forwarding `info` signed with `secret`.
+    async fn velocity_login<R, W>(
+        conn: miners_net::conn::Connection<R, W>,
+        info: &VelocityInfo,
+        secret: &[u8],
+    ) -> Result<(), client::Error>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let version = ProtocolVersion::new(404).unwrap();
+        let mut conn = ClientConnection::new(conn, version);
+        conn.write(SbHandshaking::Handshake0(Handshake0 {
+            protocol_version: *version,
+            server_address: "localhost".into(),
+            server_port: 25565,
+            next_state: NextState0::Login,
+        }))
+        .await?;
+        let Ok(Next::Login(mut conn)) = conn.next() else {
+            unreachable!("the handshake moves on to login")
+        };
+        conn.write(SbLogin::LoginStart0(LoginStart0 {
+            username: info.name.as_str().into(),
+        }))
+        .await?;
+        conn.flush().await?;
+        let message_id = match conn.read().await? {
+            CbLogin::LoginPluginRequest385(request) => {
+                assert_eq!(request.channel, VELOCITY_CHANNEL);
+                request.message_id
+            }
+            _ => return Err(client::Error::UnexpectedPacket),
+        };
+        conn.write(SbLogin::LoginPluginResponse385(LoginPluginResponse385 {
+            message_id,
+            data: Some(info.encode(secret).into()),
+        }))
+        .await?;
+        conn.flush().await?;
+        loop {
+            match conn.read().await? {
+                CbLogin::SetCompression27(_) => {}
+                CbLogin::Success5(_) => return Ok(()),
+                CbLogin::Disconnect0(disconnect) => {
+                    return Err(client::Error::Disconnected(disconnect.reason.into_owned()))
+                }
+                _ => return Err(client::Error::UnexpectedPacket),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn velocity() {
+        let acceptor = Acceptor::new(ServerConfig {
+            forwarding: Forwarding::Velocity(b"secret".to_vec()),
+            ..offline()
+        })
+        .unwrap();
+        let info = VelocityInfo {
+            address: IpAddr::from([10, 0, 0, 1]),
+            uuid: Uuid::from_u128(1),
+            name: "steve".into(),
+            properties: vec![ProfileProperty {
+                name: "textures".into(),
+                value: "e30=".into(),
+                signature: None,
+            }],
+        };
+        let (client, server) = duplex(1024);
+        let client = velocity_login(client, &info, b"secret");
+        let (client, player) = zip(client, acceptor.accept(server)).await;
+        client.unwrap();
+        let player = player.unwrap().unwrap();
+        assert_eq!((player.uuid, player.name.as_str()), (info.uuid, "steve"));
+        assert_eq!(player.properties, info.properties);
+        assert_eq!(player.forwarded_address, Some(info.address));
+
+        let (client, server) = duplex(1024);
+        let client = velocity_login(client, &info, b"wrong");
+        let (client, player) = zip(client, acceptor.accept(server)).await;
+        let Err(client::Error::Disconnected(reason)) = client else {
+            panic!("the client wasn't disconnected")
+        };
+        let reason: serde_json::Value = serde_json::from_str(&reason).unwrap();
+        assert_eq!(
+            reason["text"],
+            "IP forwarding is enabled, but the proxy forwarded nothing"
+        );
+        assert!(matches!(
+            player,
+            Err(Error::Forwarding(ForwardingError::InvalidSignature))
+        ));
+
+        // vanilla clients don't understand the request
+        let (client, server) = duplex(1024);
+        let version = ProtocolVersion::new(404).unwrap();
+        let client = async {
+            let http = reqwest::Client::new();
+            let profile = Profile::offline("steve");
+            client::login(client, "localhost", 25565, version, &profile, &http).await
+        };
+        let (client, player) = zip(client, acceptor.accept(server)).await;
+        assert!(matches!(client, Err(client::Error::Disconnected(_))));
+        assert!(matches!(
+            player,
+            Err(Error::Forwarding(ForwardingError::NotForwarded))
+        ));
+    }
+
+    /// Sends the handshake moving on to `next_state`.
+    async fn handshake<R, W>(
+        conn: miners_net::conn::Connection<R, W>,
+        next_state: NextState0,
+    ) -> Next<crate::conn::Client, R, W>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let version = ProtocolVersion::new(47).unwrap();
+        let mut conn = ClientConnection::new(conn, version);
+        conn.write(SbHandshaking::Handshake0(Handshake0 {
+            protocol_version: *version,
+            server_address: "localhost".into(),
+            server_port: 25565,
+            next_state,
+        }))
+        .await
+        .unwrap();
+        conn.flush().await.unwrap();
+        let Ok(next) = conn.next() else {
+            unreachable!("the handshake moves on")
+        };
+        next
+    }
+
+    #[tokio::test]
+    async fn unsupported_version() {
+        let acceptor = Acceptor::new(offline()).unwrap();
+        // the first has no login success, the second isn't a version at all
+        for protocol_version in [760, 1000] {
+            let (client, server) = duplex(1024);
+            let client = async {
+                let mut conn = ClientConnection::new(client, ProtocolVersion::new(47).unwrap());
+                conn.write(SbHandshaking::Handshake0(Handshake0 {
+                    protocol_version,
+                    server_address: "localhost".into(),
+                    server_port: 25565,
+                    next_state: NextState0::Login,
+                }))
+                .await
+                .unwrap();
+                conn.flush().await.unwrap();
+                conn
+            };
+            let (conn, accepted) = zip(client, acceptor.accept(server)).await;
+            let Err(Error::UnsupportedVersion(version)) = accepted else {
+                panic!("logged in with protocol version {protocol_version}")
+            };
+            assert_eq!(version, protocol_version);
+
+            let Ok(Next::Login(mut conn)) = conn.next() else {
+                unreachable!("the handshake moves on to login")
+            };
+            match conn.read().await {
+                Ok(CbLogin::Disconnect0(disconnect)) => {
+                    assert!(disconnect.reason.contains("Outdated server"))
+                }
+                _ => panic!("expected a disconnect"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn one_status_request() {
+        let acceptor = Acceptor::new(offline()).unwrap();
+        let (client, server) = duplex(1024);
+        let client = async {
+            let Next::Status(mut conn) = handshake(client, NextState0::Status).await else {
+                unreachable!("the handshake moves on to status")
+            };
+            for _ in 0..2 {
+                conn.write(SbStatus::Request0(Request0 {})).await.unwrap();
+            }
+            conn.flush().await.unwrap();
+            assert!(matches!(conn.read().await, Ok(CbStatus::Response0(_))));
+            conn
+        };
+        let (_conn, accepted) = zip(client, acceptor.accept(server)).await;
+        assert!(matches!(accepted, Err(Error::UnexpectedPacket)));
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        let timeout = Duration::from_millis(50);
+        let acceptor = Acceptor::new(ServerConfig {
+            timeouts: Timeouts {
+                handshake: timeout,
+                status: timeout,
+                login: timeout,
+            },
+            ..offline()
+        })
+        .unwrap();
+
+        let (_client, server) = duplex(1024);
+        let accepted = acceptor.accept(server).await;
+        assert!(matches!(accepted, Err(Error::TimedOut(State::Handshaking))));
+
+        for (next_state, state) in [
+            (NextState0::Status, State::Status),
+            (NextState0::Login, State::Login),
+        ] {
+            let (client, server) = duplex(1024);
+            let client = handshake(client, next_state);
+            let (_next, accepted) = zip(client, acceptor.accept(server)).await;
+            assert!(matches!(accepted, Err(Error::TimedOut(s)) if s == state));
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_protocol() {
+        let acceptor = Acceptor::new(ServerConfig {
+            proxy_protocol: true,
+            ..offline()
+        })
+        .unwrap();
+        let (mut client, server) = duplex(1024);
+        let version = ProtocolVersion::new(47).unwrap();
+        let header = ProxyHeader::new(
+            ProxyVersion::V1,
+            "203.0.113.7:56324".parse().unwrap(),
+            "10.0.0.2:25565".parse().unwrap(),
+        );
+        let client = async {
+            client.write_half.write_proxy_header(&header).await.unwrap();
+            let http = reqwest::Client::new();
+            let profile = Profile::offline("steve");
+            client::login(client, "localhost", 25565, version, &profile, &http)
+                .await
+                .unwrap()
+        };
+        let (_, player) = zip(client, acceptor.accept(server)).await;
+        assert_eq!(player.unwrap().unwrap().source_address, header.source());
+
+        // connections not coming from the balancer are refused
+        let (client, server) = duplex(1024);
+        let client = client::ping(client, "localhost", 25565, version);
+        let (_, accepted) = zip(client, acceptor.accept(server)).await;
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn online_login() {
+        let session = MockSession::default();
+        let acceptor = Acceptor::with_http(ServerConfig::default(), session.clone()).unwrap();
+        let mut listener = Listener::bind("127.0.0.1:0", acceptor).await.unwrap();
+        let port = listener.local_addr().port();
+        let version = ProtocolVersion::new(47).unwrap();
+        let mut profile = Profile {
+            name: "steve".into(),
+            auth: Some(Auth {
+                name: "steve".into(),
+                uuid: "069a79f444e94726a5befca90e38aaf5".into(),
+                token: "token".into(),
+                ..Default::default()
+            }),
+        };
+
+        let login = |profile: Profile| {
+            let session = session.clone();
+            tokio::spawn(async move {
+                let conn = client::connect_tcp("127.0.0.1", port).await?;
+                client::login(conn, "127.0.0.1", port, version, &profile, &session).await
+            })
+        };
+        let client = login(profile.clone());
+        let (player, _) = listener.next().await.unwrap();
+        assert_eq!(
+            player.uuid.simple().to_string(),
+            "069a79f444e94726a5befca90e38aaf5"
+        );
+        assert_eq!(player.properties[0].name, "textures");
+        client.await.unwrap().unwrap();
+
+        profile.auth.as_mut().unwrap().token = "expired".into();
+        let client = login(profile).await.unwrap();
+        assert!(matches!(
+            client,
+            Err(client::Error::Auth(miners_auth::Error::Session(SessionError { error, .. })))
+                if error == "ForbiddenOperationException"
+        ));
+    }
+
+    #[test]
+    fn offline_uuid_matches_vanilla() {
+        assert_eq!(
+            offline_uuid("Notch").to_string(),
+            "b50ad385-829d-3141-a216-7e7d7539ba7f"
+        );
+    }
+}